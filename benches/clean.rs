@@ -0,0 +1,77 @@
+//! Cost of `clean_content`, which runs on every streamed token.
+//!
+//! `cargo bench --bench clean`. Each input is cleaned three ways: the full default rules, the
+//! regex passes alone (whitespace normalization disabled), and the same patterns folded into one
+//! alternation, to see what merging the passes would buy.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use regex::Regex;
+
+// A binary crate has no library to link against, so the module is compiled in directly
+#[allow(dead_code)]
+#[path = "../src/clean.rs"]
+mod clean;
+
+use clean::{clean_content, CleanState, CleaningRules, ModelRules};
+
+/// The default stripping rules as a single pattern
+const COMBINED_PATTERN: &str = r"\[control_\d+\]|<unk>|\[TOOL_CALLS\]|\[TOOL_RESULTS\]";
+
+/// Typical Ollama tokens, cleaned one at a time as a stream
+fn short_tokens() -> Vec<String> {
+    let tokens = ["Hello", ",", " wor", "ld", "!", " How", "'s", " it", " going", "?", "\n\n", "1", ".", " item"];
+    tokens.iter().cycle().take(200).map(|token| token.to_string()).collect()
+}
+
+/// A paragraph arriving as one chunk, with the junk the rules exist to strip
+fn paragraph() -> Vec<String> {
+    let sentence = "The quick [control_12]brown fox<unk> jumps  over the   lazy dog.[control_7] ";
+    vec![format!("{}\n\n[TOOL_CALLS]{}", sentence.repeat(8), sentence.repeat(4))]
+}
+
+/// Fenced code, which whitespace normalization and `prose_only` rules skip
+fn code_block() -> Vec<String> {
+    let body = concat!(
+        "fn main() {\n",
+        "    let values = vec![1, 2, 3];\n",
+        "    for value in values {\n",
+        "        println!(\"{}\", value);\n",
+        "    }\n",
+        "}\n",
+    );
+    vec![format!("Here you go:\n\n```rust\n{}```\n\nThat prints each value.", body.repeat(4))]
+}
+
+fn clean_stream(tokens: &[String], rules: &CleaningRules) -> usize {
+    let mut state = CleanState::default();
+    tokens.iter().map(|token| clean_content(token, rules, &mut state).len()).sum()
+}
+
+fn combined_stream(tokens: &[String], pattern: &Regex) -> usize {
+    tokens.iter().map(|token| pattern.replace_all(token, "").len()).sum()
+}
+
+fn bench_clean_content(c: &mut Criterion) {
+    let defaults = CleaningRules::default();
+    let regex_only = ModelRules::from_json(r#"{"disabled": ["whitespace"]}"#).unwrap();
+    let regex_only = regex_only.shared();
+    let combined = Regex::new(COMBINED_PATTERN).unwrap();
+
+    let mut group = c.benchmark_group("clean_content");
+    for (name, tokens) in [("short_tokens", short_tokens()), ("paragraph", paragraph()), ("code_block", code_block())] {
+        group.throughput(Throughput::Bytes(tokens.iter().map(String::len).sum::<usize>() as u64));
+        group.bench_with_input(BenchmarkId::new("default_rules", name), &tokens, |b, tokens| {
+            b.iter(|| clean_stream(tokens, &defaults))
+        });
+        group.bench_with_input(BenchmarkId::new("regex_passes", name), &tokens, |b, tokens| {
+            b.iter(|| clean_stream(tokens, regex_only))
+        });
+        group.bench_with_input(BenchmarkId::new("single_alternation", name), &tokens, |b, tokens| {
+            b.iter(|| combined_stream(tokens, &combined))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_clean_content);
+criterion_main!(benches);
@@ -0,0 +1,90 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::{
+    extract::{Path, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use futures::future::AbortHandle;
+use tracing::info;
+
+use crate::{AppError, AppState, REQUEST_ID_HEADER};
+
+tokio::task_local! {
+    /// `X-Request-Id` of the chat request being handled, for `spawn_relay` to register its task under
+    static REQUEST_ID: String;
+}
+
+/// Generations still running, by the `X-Request-Id` of the request that started them, so
+/// `POST /abort/:request_id` can cancel one from outside its connection
+#[derive(Debug, Default)]
+pub struct InFlight {
+    tasks: Mutex<HashMap<String, (u64, AbortHandle)>>,
+    /// Tells apart entries of requests sent with the same ID, so one ending doesn't unregister another
+    next: AtomicU64,
+}
+
+impl InFlight {
+    /// Register `abort` under the current request's ID; the entry goes when the returned guard is dropped.
+    /// `None` outside a chat request, e.g. for a WebSocket, which has its own `stop`.
+    pub fn register(self: &Arc<Self>, abort: AbortHandle) -> Option<InFlightEntry> {
+        let id = REQUEST_ID.try_with(Clone::clone).ok()?;
+        let token = self.next.fetch_add(1, Ordering::Relaxed);
+        self.tasks.lock().unwrap().insert(id.clone(), (token, abort));
+        Some(InFlightEntry { in_flight: self.clone(), id, token })
+    }
+
+    /// Cancel the generation started by request `id`; false when there is none
+    fn abort(&self, id: &str) -> bool {
+        match self.tasks.lock().unwrap().remove(id) {
+            Some((_, abort)) => {
+                abort.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A generation's place in `InFlight`, held by its task until it ends
+#[derive(Debug)]
+pub struct InFlightEntry {
+    in_flight: Arc<InFlight>,
+    id: String,
+    token: u64,
+}
+
+impl Drop for InFlightEntry {
+    fn drop(&mut self) {
+        let mut tasks = self.in_flight.tasks.lock().unwrap();
+        if tasks.get(&self.id).is_some_and(|(token, _)| *token == self.token) {
+            tasks.remove(&self.id);
+        }
+    }
+}
+
+/// Make the request's ID visible to the generations its handler starts
+pub async fn scope_request_id(request: Request, next: Next) -> Response {
+    let id = request.headers().get(REQUEST_ID_HEADER).and_then(|id| id.to_str().ok()).map(str::to_string);
+    match id {
+        Some(id) => REQUEST_ID.scope(id, next.run(request)).await,
+        None => next.run(request).await,
+    }
+}
+
+/// `POST /abort/:request_id`: stop the generation started by the request with that `X-Request-Id`,
+/// closing its upstream connection. 404 when it's unknown or has already finished.
+pub async fn abort_handler(State(state): State<AppState>, Path(id): Path<String>) -> Result<StatusCode, AppError> {
+    if !state.in_flight.abort(&id) {
+        return Err(AppError::NotFound(format!("no generation in flight for request {:?}", id)));
+    }
+    info!(request_id = %id, "✋ Generation aborted");
+    Ok(StatusCode::NO_CONTENT)
+}
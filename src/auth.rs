@@ -0,0 +1,57 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{AppError, AppState};
+
+/// Middleware for the chat routes: 401 unless `Authorization: Bearer <key>` names a configured key.
+/// With no keys configured every request passes, so local development needs no setup.
+pub async fn require_api_key(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let keys = &state.config.api_keys;
+    if keys.is_empty() {
+        return next.run(request).await;
+    }
+
+    match bearer_token(request.headers()) {
+        Some(token) if is_known_key(keys, token) => {
+            next.run(request).await
+        }
+        Some(_) => {
+            tracing::warn!("Rejected request with an unknown API key");
+            unauthorized("invalid API key")
+        }
+        None => unauthorized("missing API key; send `Authorization: Bearer <key>`"),
+    }
+}
+
+/// Middleware for the admin routes: like `require_api_key`, but with no keys configured they're
+/// refused outright instead of left open, since they change how the server behaves for everyone
+pub async fn require_admin_key(state: State<AppState>, request: Request, next: Next) -> Response {
+    if state.config.api_keys.is_empty() {
+        return AppError::Forbidden("admin routes are disabled until API_KEYS is configured".to_string()).into_response();
+    }
+    require_api_key(state, request, next).await
+}
+
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+/// Whether `token` is one of the configured `keys`
+pub fn is_known_key(keys: &[String], token: &str) -> bool {
+    keys.iter().any(|key| constant_time_eq(key.as_bytes(), token.as_bytes()))
+}
+
+/// Compare without bailing out at the first differing byte, so timing doesn't leak how much of a key matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn unauthorized(message: &str) -> Response {
+    AppError::Unauthorized(message.to_string()).into_response()
+}
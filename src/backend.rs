@@ -0,0 +1,374 @@
+use std::{pin::Pin, sync::Arc, time::Duration};
+
+use futures::{future::BoxFuture, Stream, StreamExt};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    config::Config,
+    upstream::{Lease, Upstreams},
+    AppError, ChatRequest, ChatStreamResponse, GenerateRequest, KeepAlive, Usage, Utf8Decoder};
+
+/// One piece of a generation as the backend produced it, before cleaning
+#[derive(Debug)]
+pub enum Chunk {
+    /// Raw assistant content
+    Content(String),
+    /// The model finished; always the last chunk of a successful generation
+    Done(Usage),
+}
+
+/// The chunks of one generation; an `Err` ends it
+pub type ChunkStream = Pin<Box<dyn Stream<Item = Result<Chunk, AppError>> + Send>>;
+
+/// A backend's own wire format, one line at a time and without the trailing newline
+pub type LineStream = Pin<Box<dyn Stream<Item = Result<String, AppError>> + Send>>;
+
+/// A model server able to stream chat completions.
+///
+/// Backends only speak their wire format: cleaning, sessions, limits and metrics are applied by
+/// `chat_stream` on top of whatever `chat` returns.
+pub trait ChatBackend: Send + Sync {
+    /// Short name for logs, e.g. `ollama`
+    fn name(&self) -> &'static str;
+
+    /// Start generating a reply to `request.messages`. Failures before the first chunk,
+    /// such as an unreachable server or an unknown model, are returned as `Err`.
+    fn chat<'a>(&'a self, request: &'a ChatRequest) -> BoxFuture<'a, Result<ChunkStream, AppError>>;
+
+    /// Complete a bare `request.prompt`, without the chat template's turns
+    fn generate<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<ChunkStream, AppError>>;
+
+    /// Like `chat`, but relaying the backend's native stream untouched, special tokens and all
+    fn chat_raw<'a>(&'a self, request: &'a ChatRequest) -> BoxFuture<'a, Result<LineStream, AppError>>;
+
+    /// Load `model` into memory without generating anything, resolving once it's ready
+    fn warm_up<'a>(&'a self, model: &'a str, keep_alive: Option<KeepAlive>) -> BoxFuture<'a, Result<(), AppError>>;
+}
+
+/// Body of an Ollama error response
+#[derive(Debug, Deserialize)]
+struct OllamaError {
+    error: String,
+}
+
+/// An unsuccessful Ollama response as an `UpstreamStatus` with the same status code.
+/// Ollama explains itself as `{"error": "..."}`, e.g. `model "x" not found, try pulling it first`.
+pub async fn upstream_error(response: reqwest::Response) -> AppError {
+    let status = response.status();
+    let detail = response.json::<OllamaError>().await.ok().map(|body| body.error).filter(|error| !error.is_empty());
+    AppError::UpstreamStatus { status, detail }
+}
+
+/// Ollama's `/api/chat`, streamed as NDJSON from whichever of the `Upstreams` is picked
+pub struct OllamaBackend {
+    client: Client,
+    config: Arc<Config>,
+    upstreams: Arc<Upstreams>,
+}
+
+impl OllamaBackend {
+    pub fn new(client: Client, config: Arc<Config>, upstreams: Arc<Upstreams>) -> Self {
+        Self { client, config, upstreams }
+    }
+
+    /// POST the request to Ollama's `path`, retrying with exponential backoff while it can't be reached
+    /// (e.g. mid-restart). Only connection failures are retried, each time on the host `pick` chooses
+    /// next, so a dead host is routed around; any HTTP response is returned as is.
+    async fn send(&self, path: &str, request: &impl Serialize) -> Result<(reqwest::Response, Lease), reqwest::Error> {
+        let attempts = self.config.connect_attempts;
+        let mut delay = self.config.retry_base_delay;
+        let mut attempt = 1;
+        loop {
+            let lease = self.upstreams.pick();
+            let result = self
+                .client
+                .post(lease.endpoint(path))
+                .timeout(self.config.request_timeout)
+                .json(request)
+                .send()
+                .await;
+            match result {
+                Err(err) if err.is_connect() => {
+                    lease.mark_unhealthy();
+                    if attempt >= attempts {
+                        return Err(err);
+                    }
+                    warn!(
+                        attempt,
+                        attempts,
+                        host = lease.url(),
+                        retry_in_ms = delay.as_millis() as u64,
+                        error = %err,
+                        "Ollama unreachable, retrying"
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = delay.saturating_mul(2);
+                    attempt += 1;
+                }
+                result => return result.map(|response| (response, lease)),
+            }
+        }
+    }
+
+    /// `send`, turning anything but a successful response into a `AppError`
+    async fn start(&self, path: &str, request: &impl Serialize) -> Result<(reqwest::Response, Lease), AppError> {
+        match self.send(path, request).await {
+            Ok((response, lease)) if response.status().is_success() => Ok((response, lease)),
+            Ok((response, _)) => {
+                let error = upstream_error(response).await;
+                warn!(%error, path, "Ollama rejected the request");
+                Err(error)
+            }
+            Err(err) => {
+                warn!(error = ?err, "Error fetching response");
+                Err(AppError::from_reqwest(&err, self.config.request_timeout))
+            }
+        }
+    }
+}
+
+impl ChatBackend for OllamaBackend {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    fn chat<'a>(&'a self, request: &'a ChatRequest) -> BoxFuture<'a, Result<ChunkStream, AppError>> {
+        Box::pin(async move {
+            let (response, lease) = self.start("/api/chat", request).await?;
+            Ok(ndjson_chunks(ndjson_lines(response, lease, self.config.request_timeout)))
+        })
+    }
+
+    fn generate<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<ChunkStream, AppError>> {
+        Box::pin(async move {
+            // Same NDJSON framing as `/api/chat`; the text comes as `response` instead of `message.content`
+            let (response, lease) = self.start("/api/generate", request).await?;
+            Ok(ndjson_chunks(ndjson_lines(response, lease, self.config.request_timeout)))
+        })
+    }
+
+    fn chat_raw<'a>(&'a self, request: &'a ChatRequest) -> BoxFuture<'a, Result<LineStream, AppError>> {
+        Box::pin(async move {
+            let (response, lease) = self.start("/api/chat", request).await?;
+            Ok(ndjson_lines(response, lease, self.config.request_timeout))
+        })
+    }
+
+    fn warm_up<'a>(&'a self, model: &'a str, keep_alive: Option<KeepAlive>) -> BoxFuture<'a, Result<(), AppError>> {
+        Box::pin(async move {
+            // A chat without messages makes Ollama load the model and answer as soon as it's in memory
+            let request = ChatRequest {
+                model: model.to_string(),
+                messages: Vec::new(),
+                stream: false,
+                system: None,
+                options: None,
+                keep_alive,
+                format: None,
+                provider: None,
+            };
+            let (response, _lease) = self.start("/api/chat", &request).await?;
+            response.bytes().await.map_err(|err| AppError::from_reqwest(&err, self.config.request_timeout))?;
+            Ok(())
+        })
+    }
+}
+
+/// End `chunks` with `AppError::Stalled` once `idle` passes without a chunk, for a model that stops
+/// producing tokens while its connection stays open. The first chunk is left to the request timeout:
+/// loading a model and reading a long prompt take far longer than any pause between tokens.
+pub fn end_when_idle(mut chunks: ChunkStream, idle: Duration) -> ChunkStream {
+    Box::pin(async_stream::stream! {
+        let mut started = false;
+        loop {
+            let chunk = if started {
+                match tokio::time::timeout(idle, chunks.next()).await {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        yield Err(AppError::Stalled(idle));
+                        return;
+                    }
+                }
+            } else {
+                chunks.next().await
+            };
+            match chunk {
+                Some(chunk) => {
+                    started = true;
+                    yield chunk;
+                }
+                None => return,
+            }
+        }
+    })
+}
+
+/// `chunks`, but when the connection drops part way the request is sent again, up to `attempts`
+/// times, and the new stream picks up after the content already delivered. Only sound for
+/// deterministic requests: if the regenerated text doesn't start with what was delivered, the
+/// stream ends with the original error rather than splicing two different answers together.
+pub fn resume_on_disconnect(
+    backend: Arc<dyn ChatBackend>,
+    request: ChatRequest,
+    mut chunks: ChunkStream,
+    attempts: u32,
+) -> ChunkStream {
+    Box::pin(async_stream::stream! {
+        let mut delivered = String::new();
+        // How much of `delivered` the current stream has produced again
+        let mut replayed = 0;
+        let mut reconnects = 0;
+        let mut lost = None;
+
+        while let Some(chunk) = chunks.next().await {
+            match chunk {
+                Ok(Chunk::Content(content)) if replayed < delivered.len() => {
+                    let expected = &delivered[replayed..];
+                    if expected.starts_with(content.as_str()) {
+                        replayed += content.len();
+                    } else if let Some(rest) = content.strip_prefix(expected) {
+                        let rest = rest.to_string();
+                        delivered.push_str(&rest);
+                        replayed = delivered.len();
+                        yield Ok(Chunk::Content(rest));
+                    } else {
+                        warn!(reconnects, "Regenerated reply differs from what was sent, giving up resuming");
+                        yield Err(AppError::ConnectionLost(lost.take().unwrap_or_default()));
+                        return;
+                    }
+                }
+                Ok(Chunk::Content(content)) => {
+                    delivered.push_str(&content);
+                    replayed = delivered.len();
+                    yield Ok(Chunk::Content(content));
+                }
+                Ok(Chunk::Done(_)) if replayed < delivered.len() => {
+                    warn!(reconnects, "Regenerated reply is shorter than what was sent, giving up resuming");
+                    yield Err(AppError::ConnectionLost(lost.take().unwrap_or_default()));
+                    return;
+                }
+                Ok(done) => yield Ok(done),
+                Err(AppError::ConnectionLost(reason)) if reconnects < attempts => {
+                    reconnects += 1;
+                    warn!(reconnects, attempts, delivered = delivered.len(), %reason, "Ollama stream dropped, reconnecting");
+                    chunks = match backend.chat(&request).await {
+                        Ok(chunks) => chunks,
+                        Err(error) => {
+                            yield Err(error);
+                            return;
+                        }
+                    };
+                    replayed = 0;
+                    lost = Some(reason);
+                }
+                Err(error) => {
+                    yield Err(error);
+                    return;
+                }
+            }
+        }
+    })
+}
+
+/// Split a streamed body, such as Ollama's NDJSON, into lines. Network chunk boundaries don't line up
+/// with object (or even UTF-8) boundaries, so text is buffered until a line is complete.
+/// Dropping the stream drops `response`, which closes the connection and stops the generation:
+/// hyper never hands a connection back to the pool with part of a body still unread.
+/// `lease` lives as long as the stream, so an Ollama host is counted as busy until it ends.
+pub fn ndjson_lines(response: reqwest::Response, lease: impl Send + 'static, request_timeout: Duration) -> LineStream {
+    Box::pin(async_stream::stream! {
+        let _lease = lease;
+        let mut body = response.bytes_stream();
+        let mut decoder = Utf8Decoder::default();
+        let mut buffer = String::new();
+
+        while let Some(bytes) = body.next().await {
+            let bytes = match bytes {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    warn!(error = ?err, "Error reading Ollama stream");
+                    yield Err(if err.is_timeout() {
+                        AppError::Timeout(request_timeout)
+                    } else {
+                        AppError::ConnectionLost(err.to_string())
+                    });
+                    return;
+                }
+            };
+            buffer.push_str(&decoder.decode(&bytes));
+            while let Some(pos) = buffer.find('\n') {
+                let line: String = buffer.drain(..=pos).collect();
+                let line = line.trim_end_matches(['\r', '\n']);
+                if !line.is_empty() {
+                    yield Ok(line.to_string());
+                }
+            }
+        }
+
+        // Upstream ended without a trailing newline: pass on what's left
+        buffer.push_str(&decoder.finish());
+        if !buffer.trim().is_empty() {
+            yield Ok(buffer);
+        }
+    })
+}
+
+/// Parse NDJSON lines into chunks, stopping at `done` rather than waiting for the upstream to close
+fn ndjson_chunks(mut lines: LineStream) -> ChunkStream {
+    Box::pin(async_stream::stream! {
+        while let Some(line) = lines.next().await {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    yield Err(err);
+                    return;
+                }
+            };
+            let chunks = match parse_line(&line) {
+                Ok(chunks) => chunks,
+                Err(err) => {
+                    warn!(%err, "Ollama failed mid-stream");
+                    yield Err(err);
+                    return;
+                }
+            };
+            for chunk in chunks {
+                let done = matches!(chunk, Chunk::Done(_));
+                yield Ok(chunk);
+                if done {
+                    return;
+                }
+            }
+        }
+    })
+}
+
+/// Parse one complete NDJSON line, of either `/api/chat` or `/api/generate`, into the chunks it carries.
+/// A generation that fails after the 200 has gone out ends with `{"error": "..."}` instead, which
+/// comes back as the `Err` to end the stream with; lines that are neither are skipped.
+fn parse_line(line: &str) -> Result<Vec<Chunk>, AppError> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(Vec::new());
+    }
+    // Checked first: every field of a regular line is optional, so this one would pass as an empty line
+    if let Ok(OllamaError { error }) = serde_json::from_str::<OllamaError>(line) {
+        // What Ollama would have answered with, had it failed before starting
+        let detail = Some(error).filter(|error| !error.is_empty());
+        return Err(AppError::UpstreamStatus { status: StatusCode::INTERNAL_SERVER_ERROR, detail });
+    }
+    let Ok(parsed) = serde_json::from_str::<ChatStreamResponse>(line) else {
+        return Ok(Vec::new());
+    };
+
+    let mut chunks = Vec::new();
+    if let Some(content) = parsed.content().filter(|content| !content.is_empty()) {
+        chunks.push(Chunk::Content(content.to_string()));
+    }
+    if parsed.done {
+        chunks.push(Chunk::Done(Usage::from_final_line(&parsed)));
+    }
+    Ok(chunks)
+}
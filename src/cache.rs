@@ -0,0 +1,81 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::StreamExt;
+use lru::LruCache;
+
+use crate::{
+    backend::{Chunk, ChunkStream},
+    ChatRequest, Usage,
+};
+
+/// One finished generation, as the backend produced it
+#[derive(Debug)]
+struct CachedReply {
+    contents: Vec<String>,
+    usage: Usage,
+    stored: Instant,
+}
+
+/// Completions of deterministic requests (temperature 0 or a fixed seed), so asking the same
+/// thing again is answered without the backend. Replies are kept as raw chunks and replayed one
+/// by one, so cleaning, stop sequences and sessions apply exactly as they did the first time.
+#[derive(Debug)]
+pub struct ResponseCache {
+    entries: Mutex<LruCache<String, CachedReply>>,
+    ttl: Duration,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: NonZeroUsize, ttl: Duration) -> Self {
+        Self { entries: Mutex::new(LruCache::new(capacity)), ttl }
+    }
+
+    /// What identifies `request`'s answer; `None` when sampling makes it unrepeatable
+    pub fn key(request: &ChatRequest) -> Option<String> {
+        let options = request.options.as_ref().filter(|options| options.is_deterministic())?;
+        serde_json::to_string(&(&request.model, &request.messages, options, &request.format, &request.provider)).ok()
+    }
+
+    /// Replay the stored reply for `key`, unless there is none or it has expired
+    pub fn get(&self, key: &str) -> Option<ChunkStream> {
+        let mut entries = self.entries.lock().unwrap();
+        let reply = entries.get(key)?;
+        if reply.stored.elapsed() > self.ttl {
+            entries.pop(key);
+            return None;
+        }
+        let chunks: Vec<_> = reply
+            .contents
+            .iter()
+            .cloned()
+            .map(Chunk::Content)
+            .chain([Chunk::Done(reply.usage.clone())])
+            .map(Ok)
+            .collect();
+        Some(Box::pin(futures::stream::iter(chunks)))
+    }
+
+    /// Pass `chunks` through, storing them under `key` once they reach `Done`.
+    /// A generation that fails or is abandoned part way is never stored.
+    pub fn record(self: Arc<Self>, key: String, mut chunks: ChunkStream) -> ChunkStream {
+        Box::pin(async_stream::stream! {
+            let mut contents = Vec::new();
+            while let Some(chunk) = chunks.next().await {
+                match &chunk {
+                    Ok(Chunk::Content(content)) => contents.push(content.clone()),
+                    Ok(Chunk::Done(usage)) => {
+                        let contents = std::mem::take(&mut contents);
+                        let reply = CachedReply { contents, usage: usage.clone(), stored: Instant::now() };
+                        self.entries.lock().unwrap().put(key.clone(), reply);
+                    }
+                    Err(_) => {}
+                }
+                yield chunk;
+            }
+        })
+    }
+}
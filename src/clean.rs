@@ -0,0 +1,420 @@
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+
+use regex::Regex;
+use serde::Deserialize;
+
+/// One find-and-replace pass applied to every streamed token
+#[derive(Debug, Clone)]
+pub struct CleanRule {
+    pub name: String,
+    pub pattern: Regex,
+    pub replacement: String,
+    /// Skip text inside ``` fences, so code keeps its indentation
+    pub prose_only: bool,
+}
+
+impl CleanRule {
+    fn new(name: &str, pattern: &str, replacement: &str, prose_only: bool) -> Result<Self, regex::Error> {
+        Ok(Self {
+            name: name.to_string(),
+            pattern: Regex::new(pattern)?,
+            replacement: replacement.to_string(),
+            prose_only,
+        })
+    }
+
+    fn apply(&self, text: String) -> String {
+        match self.pattern.replace_all(&text, self.replacement.as_str()) {
+            Cow::Owned(replaced) => replaced,
+            Cow::Borrowed(_) => text,
+        }
+    }
+}
+
+/// Ordered rule set used by `clean_content`, built once at startup
+#[derive(Debug, Clone)]
+pub struct CleaningRules {
+    rules: Vec<CleanRule>,
+    /// Normalize whitespace after the rules (`WHITESPACE_RULE`). Runs over the stream as a whole,
+    /// since a run of spaces or newlines is often split across tokens.
+    collapse_whitespace: bool,
+}
+
+/// On-disk shape of a rules file, e.g.
+///
+/// ```json
+/// {
+///   "disabled": ["tool_markers"],
+///   "rules": [{ "name": "eot", "pattern": "<\\|eot_id\\|>", "replacement": "", "prose_only": false }],
+///   "models": {
+///     "qwen2.5": { "rules": [{ "name": "im_end", "pattern": "<\\|im_end\\|>" }] }
+///   }
+/// }
+/// ```
+///
+/// Custom rules run after the default stripping rules but before whitespace collapsing.
+/// Set `"include_defaults": false` to start from an empty set instead, or disable `whitespace`
+/// to pass the model's spacing through untouched.
+///
+/// Each `models` entry has the same shape and applies to that model only, building on the set
+/// above rather than the built-in one: its rules run after the shared ones, and `disabled` and
+/// `"include_defaults": false` take shared rules away. Other models get the shared set.
+#[derive(Debug, Deserialize)]
+struct RulesFile {
+    #[serde(flatten)]
+    shared: RuleSet,
+    #[serde(default)]
+    models: HashMap<String, RuleSet>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RuleSet {
+    #[serde(default = "default_true")]
+    include_defaults: bool,
+    #[serde(default)]
+    disabled: Vec<String>,
+    #[serde(default)]
+    rules: Vec<RuleSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RuleSpec {
+    name: String,
+    pattern: String,
+    #[serde(default)]
+    replacement: String,
+    #[serde(default)]
+    prose_only: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl RuleSet {
+    /// The rules of `base` (unless `include_defaults` is off), then these, minus the disabled ones
+    fn build(&self, base: &CleaningRules) -> Result<CleaningRules, String> {
+        let mut custom = Vec::with_capacity(self.rules.len());
+        for spec in &self.rules {
+            let rule = CleanRule::new(&spec.name, &spec.pattern, &spec.replacement, spec.prose_only)
+                .map_err(|err| format!("rule {:?}: {}", spec.name, err))?;
+            custom.push(rule);
+        }
+
+        let mut rules = if self.include_defaults { base.rules.clone() } else { Vec::new() };
+        rules.extend(custom);
+        rules.retain(|rule| !self.disabled.contains(&rule.name));
+        let collapse_whitespace = self.include_defaults
+            && base.collapse_whitespace
+            && !self.disabled.iter().any(|name| name == WHITESPACE_RULE);
+
+        Ok(CleaningRules { rules, collapse_whitespace })
+    }
+}
+
+/// Name of the default whitespace normalization, which always runs last
+const WHITESPACE_RULE: &str = "whitespace";
+const TOOL_MARKERS_RULE: &str = "tool_markers";
+
+impl Default for CleaningRules {
+    /// Strip control tokens, `<unk>`, and tool-call markers, then normalize whitespace
+    fn default() -> Self {
+        let rules = [
+            ("control_tokens", r"\[control_\d+\]", "", false),
+            ("unknown_tokens", r"<unk>", "", false),
+            (TOOL_MARKERS_RULE, r"(\[TOOL_CALLS\]|\[TOOL_RESULTS\])", "", false),
+        ]
+        .into_iter()
+        .map(|(name, pattern, replacement, prose_only)| CleanRule::new(name, pattern, replacement, prose_only).unwrap())
+        .collect();
+        Self { rules, collapse_whitespace: true }
+    }
+}
+
+impl CleaningRules {
+    /// Keep `[TOOL_CALLS]` in the content for `ToolCallParser`, still stripping `[TOOL_RESULTS]`
+    pub fn keep_tool_calls(&mut self) {
+        for rule in self.rules.iter_mut().filter(|rule| rule.name == TOOL_MARKERS_RULE) {
+            *rule = CleanRule::new("tool_results", r"\[TOOL_RESULTS\]", "", false).unwrap();
+        }
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.rules.iter().map(|rule| rule.name.as_str()).chain(self.collapse_whitespace.then_some(WHITESPACE_RULE))
+    }
+}
+
+/// The rules to clean each model's output with: its own set from the rules file's `models`,
+/// else the shared one
+#[derive(Debug, Clone, Default)]
+pub struct ModelRules {
+    shared: Arc<CleaningRules>,
+    /// Keyed by lowercase model name, without the implied `:latest` tag
+    models: HashMap<String, Arc<CleaningRules>>,
+}
+
+impl ModelRules {
+    /// Parse a JSON rules file (see `RulesFile`), per-model sets included
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let file: RulesFile = serde_json::from_str(json).map_err(|err| err.to_string())?;
+        let shared = file.shared.build(&CleaningRules::default())?;
+        let mut models = HashMap::with_capacity(file.models.len());
+        for (model, set) in &file.models {
+            let rules = set.build(&shared).map_err(|err| format!("models.{}: {}", model, err))?;
+            models.insert(model_key(&model.trim().to_lowercase()).to_string(), Arc::new(rules));
+        }
+        Ok(Self { shared: Arc::new(shared), models })
+    }
+
+    /// The set for `model`. An entry for a bare name such as `llama3` also covers its tags (`llama3:8b`),
+    /// while `llama3:8b` only covers itself.
+    pub fn for_model(&self, model: &str) -> Arc<CleaningRules> {
+        let model = model.trim().to_lowercase();
+        let key = model_key(&model);
+        let family = key.split_once(':').map_or(key, |(name, _)| name);
+        self.models.get(key).or_else(|| self.models.get(family)).unwrap_or(&self.shared).clone()
+    }
+
+    /// `CleaningRules::keep_tool_calls` on every set
+    pub fn keep_tool_calls(&mut self) {
+        for rules in std::iter::once(&mut self.shared).chain(self.models.values_mut()) {
+            Arc::make_mut(rules).keep_tool_calls();
+        }
+    }
+
+    pub fn shared(&self) -> &CleaningRules {
+        &self.shared
+    }
+
+    /// Models with a set of their own
+    pub fn models(&self) -> impl Iterator<Item = &str> {
+        self.models.keys().map(String::as_str)
+    }
+}
+
+/// `Mistral:latest` and `mistral` name the same model
+fn model_key(model: &str) -> &str {
+    model.strip_suffix(":latest").unwrap_or(model)
+}
+
+/// What `clean_content` carries from one token of a stream to the next
+#[derive(Debug, Default)]
+pub struct CleanState {
+    fence: FenceState,
+    whitespace: WhitespaceRun,
+}
+
+/// The run of whitespace the stream currently ends in, which the next token may continue
+#[derive(Debug, Default)]
+struct WhitespaceRun {
+    /// Line breaks sent for this run, at most two (one blank line)
+    newlines: usize,
+    /// A space was sent for this run, before any line break
+    spaced: bool,
+    /// Last character was a `\r`, so a `\n` right after it is the same line break
+    after_cr: bool,
+}
+
+/// Tracks whether a stream is inside a ``` fence. Kept per stream, because fences
+/// (and even the three backticks themselves) are split across tokens.
+#[derive(Debug, Default)]
+struct FenceState {
+    in_code: bool,
+    /// Consecutive backticks seen so far, possibly carried over from the previous token
+    backticks: usize,
+}
+
+impl FenceState {
+    /// Split `text` into `(is_code, segment)` runs, advancing the fence state
+    fn split<'a>(&mut self, text: &'a str) -> Vec<(bool, &'a str)> {
+        let mut segments = Vec::new();
+        let mut start = 0;
+        for (i, ch) in text.char_indices() {
+            if ch != '`' {
+                self.backticks = 0;
+                continue;
+            }
+            self.backticks += 1;
+            if self.backticks == 3 {
+                // The fence itself stays with the segment it closes or opens
+                let end = i + 1;
+                segments.push((self.in_code, &text[start..end]));
+                start = end;
+                self.in_code = !self.in_code;
+                self.backticks = 0;
+            }
+        }
+        if start < text.len() {
+            segments.push((self.in_code, &text[start..]));
+        }
+        segments
+    }
+}
+
+/// **Fast cleaning of response content**
+///
+/// `prose_only` rules and whitespace normalization are left out of fenced code. `state` carries
+/// the fence, and the whitespace run the text ends in, from one token to the next.
+pub fn clean_content(raw: &str, rules: &CleaningRules, state: &mut CleanState) -> String {
+    let mut text = raw.to_string();
+    for rule in rules.rules.iter().filter(|rule| !rule.prose_only) {
+        text = rule.apply(text);
+    }
+
+    let prose_rules: Vec<_> = rules.rules.iter().filter(|rule| rule.prose_only).collect();
+    if prose_rules.is_empty() && !rules.collapse_whitespace {
+        return text;
+    }
+    let mut out = String::with_capacity(text.len());
+    for (is_code, segment) in state.fence.split(&text) {
+        if is_code {
+            state.whitespace = WhitespaceRun::default();
+            out.push_str(segment);
+            continue;
+        }
+        let segment = prose_rules.iter().fold(segment.to_string(), |acc, rule| rule.apply(acc));
+        if rules.collapse_whitespace {
+            normalize_whitespace(&segment, &mut state.whitespace, &mut out);
+        } else {
+            out.push_str(&segment);
+        }
+    }
+    out
+}
+
+/// Copy `text` to `out` with each run of whitespace normalized: spaces and tabs within a line
+/// become one space, while line breaks are kept (up to one blank line) along with the indentation
+/// that follows them, so lists and paragraphs survive. Output is never held back; `run` remembers
+/// what was already sent, so a run split across tokens comes out the same as if it weren't.
+fn normalize_whitespace(text: &str, run: &mut WhitespaceRun, out: &mut String) {
+    for ch in text.chars() {
+        let after_cr = std::mem::take(&mut run.after_cr);
+        match ch {
+            '\n' if after_cr => {}
+            '\n' | '\r' => {
+                if run.newlines < 2 {
+                    out.push('\n');
+                }
+                run.newlines += 1;
+                run.after_cr = ch == '\r';
+            }
+            // Indentation
+            ch if ch.is_whitespace() && run.newlines > 0 => out.push(ch),
+            ch if ch.is_whitespace() => {
+                if !run.spaced {
+                    out.push(' ');
+                    run.spaced = true;
+                }
+            }
+            ch => {
+                *run = WhitespaceRun::default();
+                out.push(ch);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Clean a whole string with the default rules, as if it were a single token
+    fn clean(raw: &str) -> String {
+        clean_tokens(&[raw])
+    }
+
+    /// Clean `tokens` as one stream with the default rules, joining the output
+    fn clean_tokens(tokens: &[&str]) -> String {
+        let rules = CleaningRules::default();
+        let mut state = CleanState::default();
+        tokens.iter().map(|token| clean_content(token, &rules, &mut state)).collect()
+    }
+
+    #[test]
+    fn control_tokens_are_stripped() {
+        assert_eq!(clean("Hello[control_42] world[control_7]"), "Hello world");
+    }
+
+    #[test]
+    fn unknown_tokens_are_removed() {
+        assert_eq!(clean("<unk>Hel<unk>lo"), "Hello");
+    }
+
+    #[test]
+    fn tool_markers_are_removed() {
+        assert_eq!(clean("[TOOL_CALLS]call[TOOL_RESULTS]result"), "callresult");
+    }
+
+    #[test]
+    fn whitespace_runs_collapse_to_one_space() {
+        assert_eq!(clean("a   b\tc \t d"), "a b c d");
+    }
+
+    #[test]
+    fn all_rules_combine() {
+        assert_eq!(clean("[TOOL_CALLS] Hi<unk>  there[control_3]\n\n[TOOL_RESULTS]friend"), " Hi there\n\nfriend");
+    }
+
+    #[test]
+    fn numbered_lists_keep_their_lines() {
+        let tokens = ["Steps:", "\n", "1.", " Boil", " water", "\n", "2", ". Add", " tea\n", "   - green", "\n3. Wait"];
+        assert_eq!(clean_tokens(&tokens), "Steps:\n1. Boil water\n2. Add tea\n   - green\n3. Wait");
+    }
+
+    #[test]
+    fn paragraph_breaks_survive_token_boundaries() {
+        let tokens = ["First paragraph.", "\n", "\n", "\n\n", "Second", "  ", " one.", "\r\n\r\nThird."];
+        assert_eq!(clean_tokens(&tokens), "First paragraph.\n\nSecond one.\n\nThird.");
+    }
+
+    #[test]
+    fn whitespace_can_be_left_alone() {
+        let rules = ModelRules::from_json(r#"{"disabled": ["whitespace"]}"#).unwrap();
+        let rules = rules.shared();
+        let raw = "a   b\n\n\nc";
+        assert_eq!(clean_content(raw, rules, &mut CleanState::default()), raw);
+        assert!(!rules.names().any(|name| name == WHITESPACE_RULE));
+    }
+
+    #[test]
+    fn models_get_their_own_rules_on_top_of_the_shared_ones() {
+        let rules = ModelRules::from_json(
+            r#"{
+                "rules": [{ "name": "eot", "pattern": "<\\|eot_id\\|>" }],
+                "models": {
+                    "Qwen2.5": { "rules": [{ "name": "im_end", "pattern": "<\\|im_end\\|>" }] },
+                    "phi3:mini": { "disabled": ["unknown_tokens"] }
+                }
+            }"#,
+        )
+        .unwrap();
+        let clean_for = |model: &str, raw: &str| clean_content(raw, &rules.for_model(model), &mut CleanState::default());
+
+        assert_eq!(clean_for("qwen2.5:7b", "Hi<|im_end|><|eot_id|><unk>"), "Hi");
+        assert_eq!(clean_for("mistral", "Hi<|im_end|><|eot_id|><unk>"), "Hi<|im_end|>");
+        assert_eq!(clean_for("phi3:mini", "Hi<unk><|eot_id|>"), "Hi<unk>");
+        assert_eq!(clean_for("phi3", "Hi<unk><|eot_id|>"), "Hi");
+    }
+
+    #[test]
+    fn unicode_text_is_preserved() {
+        let text = "Café ☕ naïve 日本語 — Ελληνικά 🦀";
+        assert_eq!(clean(text), text);
+    }
+
+    const MIXED: &str = "Here is   the fix:\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```\nThat's   all.";
+
+    #[test]
+    fn code_fences_keep_their_whitespace() {
+        assert_eq!(
+            clean(MIXED),
+            "Here is the fix:\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```\nThat's all."
+        );
+    }
+
+    #[test]
+    fn fences_split_across_tokens_are_tracked() {
+        let tokens = ["Here is   the fix:\n``", "`rust\nfn main() {\n", "    println!(\"hi\");", "\n}\n`", "``\nThat's   all."];
+        assert_eq!(clean_tokens(&tokens), clean(MIXED));
+    }
+}
@@ -0,0 +1,712 @@
+use std::{
+    collections::HashMap,
+    io::IsTerminal,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+const DEFAULT_MODEL: &str = "mistral";
+const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
+const DEFAULT_PORT: u16 = 8000;
+/// Fail fast when Ollama isn't listening instead of hanging the client
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 5;
+/// Upper bound on a whole generation, from connect until the last byte of the stream
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 60;
+/// Enough for bursts of concurrent streams to reuse connections without hoarding sockets
+const DEFAULT_POOL_MAX_IDLE_PER_HOST: usize = 32;
+const DEFAULT_POOL_IDLE_TIMEOUT_SECS: u64 = 90;
+/// Notices an Ollama host that vanished mid-generation well before the request timeout
+const DEFAULT_TCP_KEEPALIVE_SECS: u64 = 60;
+/// Far longer than a model ever pauses between tokens, unless it's stuck
+const DEFAULT_TOKEN_IDLE_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_RATE_LIMIT: u32 = 60;
+const DEFAULT_RATE_LIMIT_WINDOW_SECS: u64 = 60;
+const DEFAULT_CONNECT_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_DELAY_MS: u64 = 250;
+const DEFAULT_QUEUE_TIMEOUT_MS: u64 = 2000;
+const DEFAULT_WORKER_QUEUE_DEPTH: usize = 64;
+const DEFAULT_STREAM_BUFFER: u32 = 20;
+/// Well under the idle timeouts of common proxies and load balancers (typically 30-60s)
+const DEFAULT_SSE_KEEP_ALIVE_SECS: u64 = 15;
+const DEFAULT_STATIC_DIR: &str = "static";
+const DEFAULT_INDEX_FILE: &str = "index.html";
+/// Roughly 8k tokens, a common context window
+const DEFAULT_MAX_PROMPT_CHARS: usize = 32_000;
+const DEFAULT_MAX_SESSIONS: usize = 10_000;
+const DEFAULT_SESSION_TTL_SECS: u64 = 30 * 60;
+const DEFAULT_RESPONSE_CACHE_TTL_SECS: u64 = 600;
+const DEFAULT_UPSTREAM_COOLDOWN_SECS: u64 = 30;
+const DEFAULT_OPENAI_URL: &str = "https://api.openai.com";
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable, colored lines
+    Pretty,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+/// How chat requests are spread over several Ollama hosts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BalanceStrategy {
+    /// Each host in turn
+    RoundRobin,
+    /// Whichever host has the fewest requests in flight
+    LeastConnections,
+}
+
+/// Which backend generates a request's reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// The local Ollama hosts (`--ollama-url`)
+    Ollama,
+    /// The OpenAI-compatible API at `--openai-url`
+    Openai,
+}
+
+impl std::str::FromStr for Provider {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        <Self as ValueEnum>::from_str(raw, true)
+    }
+}
+
+/// Every setting, from the command line or the environment. The same keys (in snake_case) can be
+/// set in `config.toml`; flags win over environment variables, which win over the file.
+#[derive(Debug, Default, Parser, Deserialize)]
+#[command(version, about = "Streaming chat proxy in front of Ollama")]
+#[serde(default, deny_unknown_fields)]
+struct Settings {
+    /// TOML file with default settings [default: config.toml, if present]
+    #[arg(long, env = "CHATBOT_CONFIG")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+
+    /// `pretty` or `json`; filter with `RUST_LOG` [default: pretty on a terminal, json otherwise]
+    #[arg(long, env = "LOG_FORMAT", value_enum)]
+    log_format: Option<LogFormat>,
+
+    /// Address to bind the HTTP server to [default: 0.0.0.0]
+    #[arg(long, env = "CHATBOT_ADDR")]
+    addr: Option<IpAddr>,
+
+    /// Port to listen on [default: 8000]
+    #[arg(long, env = "PORT")]
+    port: Option<u16>,
+
+    /// Model used when a request doesn't name one [default: mistral]
+    #[arg(long, env = "OLLAMA_MODEL")]
+    model: Option<String>,
+
+    /// Prompt used when a query-string request has none (unset: reject such requests with 400)
+    #[arg(long, env = "DEFAULT_PROMPT")]
+    default_prompt: Option<String>,
+
+    /// Named prompts with `{{placeholders}}`, picked with `?template=` and filled from the other
+    /// parameters. Only settable in the config file, as a `[templates]` table.
+    #[arg(skip)]
+    templates: HashMap<String, String>,
+
+    /// System prompt prepended when a request doesn't bring its own
+    #[arg(long, env = "SYSTEM_PROMPT")]
+    system_prompt: Option<String>,
+
+    /// File holding the default system prompt, used when `--system-prompt` is unset
+    #[arg(long, env = "SYSTEM_PROMPT_FILE")]
+    system_prompt_file: Option<PathBuf>,
+
+    /// Ollama base URL, or a comma-separated list to balance over (`OLLAMA_HOST` is also honored)
+    /// [default: http://localhost:11434]
+    #[arg(long, env = "OLLAMA_URL")]
+    ollama_url: Option<String>,
+
+    /// `round-robin` or `least-connections`, with several Ollama URLs [default: round-robin]
+    #[arg(long, env = "OLLAMA_BALANCE", value_enum)]
+    balance_strategy: Option<BalanceStrategy>,
+
+    /// Base URL of an OpenAI-compatible API, used for requests with `provider=openai` or routed there by
+    /// `--model-providers`. The provider is enabled when this or `--openai-api-key` is set [default: https://api.openai.com]
+    #[arg(long, env = "OPENAI_BASE_URL")]
+    openai_url: Option<String>,
+
+    /// Bearer key sent to `--openai-url`
+    #[arg(long, env = "OPENAI_API_KEY", hide_env_values = true)]
+    openai_api_key: Option<String>,
+
+    /// Comma-separated `model=provider` routes, e.g. `gpt-4o-mini=openai`; other models go to Ollama
+    #[arg(long, env = "MODEL_PROVIDERS", value_delimiter = ',')]
+    model_providers: Vec<String>,
+
+    /// Estimated prompt tokens a conversation is trimmed to before it's sent, oldest messages first
+    /// (0 disables trimming) [default: 0]
+    #[arg(long, env = "CONTEXT_BUDGET_TOKENS")]
+    context_budget: Option<usize>,
+
+    /// Comma-separated `model=tokens` budgets overriding `--context-budget`, e.g. `llama3=8000,phi3=4000`
+    #[arg(long, env = "MODEL_CONTEXT_BUDGETS", value_delimiter = ',')]
+    model_context_budgets: Vec<String>,
+
+    /// Seconds an unreachable Ollama host is skipped before being tried again [default: 30]
+    #[arg(long, env = "OLLAMA_COOLDOWN_SECS")]
+    upstream_cooldown_secs: Option<u64>,
+
+    /// Seconds to wait for a TCP connection to Ollama [default: 5]
+    #[arg(long, env = "OLLAMA_CONNECT_TIMEOUT_SECS")]
+    connect_timeout_secs: Option<u64>,
+
+    /// Seconds allowed for a whole chat call, streaming included [default: 60]
+    #[arg(long, env = "OLLAMA_TIMEOUT_SECS")]
+    request_timeout_secs: Option<u64>,
+
+    /// Seconds a stream may go without a token, once it has started, before it's ended as stalled
+    /// (0 waits as long as the request timeout allows) [default: 30]
+    #[arg(long, env = "TOKEN_IDLE_TIMEOUT_SECS")]
+    token_idle_timeout_secs: Option<u64>,
+
+    /// Idle connections kept open to each upstream host for reuse [default: 32]
+    #[arg(long, env = "POOL_MAX_IDLE_PER_HOST")]
+    pool_max_idle_per_host: Option<usize>,
+
+    /// Seconds an idle pooled connection is kept before being closed (0 keeps them indefinitely) [default: 90]
+    #[arg(long, env = "POOL_IDLE_TIMEOUT_SECS")]
+    pool_idle_timeout_secs: Option<u64>,
+
+    /// Seconds between TCP keepalive probes on upstream connections (0 disables them) [default: 60]
+    #[arg(long, env = "TCP_KEEPALIVE_SECS")]
+    tcp_keepalive_secs: Option<u64>,
+
+    /// Chat requests allowed per client IP in each window (0 disables the limit) [default: 60]
+    #[arg(long, env = "RATE_LIMIT")]
+    rate_limit: Option<u32>,
+
+    /// Chat requests allowed per `--api-keys` key, and per `session_id`, in each window. Applies on top of
+    /// the per-IP limit (0 disables it) [default: 0]
+    #[arg(long, env = "SESSION_RATE_LIMIT")]
+    session_rate_limit: Option<u32>,
+
+    /// Length of the rate-limit windows in seconds [default: 60]
+    #[arg(long, env = "RATE_LIMIT_WINDOW_SECS")]
+    rate_limit_window_secs: Option<u64>,
+
+    /// Key rate limits on the first `X-Forwarded-For` hop (only behind a trusted proxy)
+    #[arg(long, env = "TRUST_FORWARDED_FOR")]
+    trust_forwarded_for: bool,
+
+    /// Comma-separated API keys accepted as `Authorization: Bearer <key>` on chat routes (unset disables auth)
+    #[arg(long, env = "API_KEYS", value_delimiter = ',', hide_env_values = true)]
+    api_keys: Vec<String>,
+
+    /// Comma-separated models requests may use, compared case-insensitively and ignoring `:latest` (unset allows any)
+    #[arg(long, env = "ALLOWED_MODELS", value_delimiter = ',')]
+    allowed_models: Vec<String>,
+
+    /// Comma-separated origins browsers may call from, e.g. `https://chat.example.com` (unset allows any);
+    /// replaceable at runtime through `PUT /admin/cors`
+    #[arg(long, env = "CORS_ALLOWED_ORIGINS", value_delimiter = ',')]
+    cors_allowed_origins: Vec<String>,
+
+    /// Attempts at reaching Ollama before a chat request gives up (1 disables retrying) [default: 3]
+    #[arg(long, env = "OLLAMA_CONNECT_ATTEMPTS")]
+    connect_attempts: Option<u32>,
+
+    /// Delay before the first connection retry in milliseconds; doubles on each further attempt [default: 250]
+    #[arg(long, env = "OLLAMA_RETRY_DELAY_MS")]
+    retry_delay_ms: Option<u64>,
+
+    /// Times a deterministic chat (fixed seed or temperature 0) is resent when the connection to Ollama drops
+    /// mid-stream, resuming after the text already delivered (0 disables) [default: 0]
+    #[arg(long, env = "OLLAMA_STREAM_RECONNECTS")]
+    stream_reconnects: Option<u32>,
+
+    /// Generations allowed to run at once across all clients (0 means unlimited) [default: 0]
+    #[arg(long, env = "MAX_GENERATIONS")]
+    max_generations: Option<usize>,
+
+    /// How long a request over the generation limit waits for a slot before getting a 503, in milliseconds [default: 2000]
+    #[arg(long, env = "GENERATION_QUEUE_TIMEOUT_MS")]
+    queue_timeout_ms: Option<u64>,
+
+    /// Worker tasks that run generations, taking them off a bounded queue (0 spawns a task per stream) [default: 0]
+    #[arg(long, env = "WORKER_POOL_SIZE")]
+    workers: Option<usize>,
+
+    /// Generations waiting for a free worker before new ones get a 503 [default: 64]
+    #[arg(long, env = "WORKER_QUEUE_DEPTH", value_parser = clap::value_parser!(u32).range(1..))]
+    queue_depth: Option<u32>,
+
+    /// Tokens buffered between the Ollama reader and each client [default: 20].
+    /// Smaller values push back on Ollama sooner when a client reads slowly; larger ones absorb
+    /// bursts and smooth delivery at the cost of memory per stream.
+    #[arg(long, env = "STREAM_BUFFER", value_parser = clap::value_parser!(u32).range(1..))]
+    stream_buffer: Option<u32>,
+
+    /// Seconds without a token before SSE streams send a `: keep-alive` comment (0 disables) [default: 15]
+    #[arg(long, env = "SSE_KEEP_ALIVE_SECS")]
+    sse_keep_alive_secs: Option<u64>,
+
+    /// Characters allowed across a request's messages before it's rejected with 413 (0 means unlimited) [default: 32000]
+    #[arg(long, env = "MAX_PROMPT_CHARS")]
+    max_prompt_chars: Option<usize>,
+
+    /// Directory served under `/static` [default: static]
+    #[arg(long, env = "STATIC_DIR")]
+    static_dir: Option<PathBuf>,
+
+    /// PEM certificate chain for serving HTTPS: the server's certificate first, then any intermediates.
+    /// Needs `--tls-key` too; without both, plain HTTP is served
+    #[arg(long, env = "TLS_CERT_FILE")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for `--tls-cert` (PKCS#8, PKCS#1 or SEC1 `-----BEGIN ... PRIVATE KEY-----` block)
+    #[arg(long, env = "TLS_KEY_FILE")]
+    tls_key: Option<PathBuf>,
+
+    /// Page served at `/`; the copy built into the binary is used when it can't be read [default: index.html]
+    #[arg(long, env = "INDEX_FILE")]
+    index_file: Option<PathBuf>,
+
+    /// Send `[TOOL_CALLS]` payloads to clients as structured frames instead of stripping them
+    #[arg(long, env = "FORWARD_TOOL_CALLS")]
+    forward_tool_calls: bool,
+
+    /// Answer every request with a canned reply instead of a model, for frontend work.
+    /// Only in debug builds, and only as a flag, so no environment or config file can turn it on.
+    #[arg(long)]
+    #[serde(skip)]
+    mock_backend: bool,
+
+    /// Log the shape of every prompt (roles, message count, length) under the `audit` target
+    #[arg(long, env = "AUDIT_LOG")]
+    audit_log: bool,
+
+    /// File of regex patterns, one per line, that reject a prompt with a 400 when any of them matches
+    #[arg(long, env = "MODERATION_FILE")]
+    moderation_file: Option<PathBuf>,
+
+    /// Also replace matches of the moderation patterns in streamed replies with `[redacted]`
+    #[arg(long, env = "MODERATION_REDACT")]
+    moderation_redact: bool,
+
+    /// Seconds a session may sit idle before it's dropped [default: 1800]
+    #[arg(long, env = "SESSION_TTL_SECS")]
+    session_ttl_secs: Option<u64>,
+
+    /// Sessions kept in memory before the least recently used is evicted (0 means unlimited) [default: 10000]
+    #[arg(long, env = "MAX_SESSIONS")]
+    max_sessions: Option<usize>,
+
+    /// SQLite file to persist session history in, so it survives restarts (unset keeps sessions in memory only)
+    #[arg(long, env = "SESSION_DB")]
+    session_db: Option<PathBuf>,
+
+    /// Replies of deterministic requests (temperature 0 or a seed) to keep and serve again (0 disables the cache)
+    #[arg(long, env = "RESPONSE_CACHE_SIZE")]
+    response_cache_size: Option<usize>,
+
+    /// Seconds a cached reply stays valid [default: 600]
+    #[arg(long, env = "RESPONSE_CACHE_TTL_SECS")]
+    response_cache_ttl_secs: Option<u64>,
+
+    /// JSON file adding, removing, or disabling token cleaning rules, for all models or per model
+    #[arg(long, env = "CLEANING_RULES_FILE")]
+    cleaning_rules: Option<PathBuf>,
+}
+
+impl Settings {
+    /// Fill whatever is unset here from `file`
+    fn or(self, file: Settings) -> Self {
+        Self {
+            config: self.config,
+            log_format: self.log_format.or(file.log_format),
+            addr: self.addr.or(file.addr),
+            port: self.port.or(file.port),
+            model: self.model.or(file.model),
+            default_prompt: self.default_prompt.or(file.default_prompt),
+            templates: file.templates,
+            system_prompt: self.system_prompt.or(file.system_prompt),
+            system_prompt_file: self.system_prompt_file.or(file.system_prompt_file),
+            ollama_url: self.ollama_url.or(file.ollama_url),
+            balance_strategy: self.balance_strategy.or(file.balance_strategy),
+            upstream_cooldown_secs: self.upstream_cooldown_secs.or(file.upstream_cooldown_secs),
+            openai_url: self.openai_url.or(file.openai_url),
+            openai_api_key: self.openai_api_key.or(file.openai_api_key),
+            model_providers: if self.model_providers.is_empty() { file.model_providers } else { self.model_providers },
+            context_budget: self.context_budget.or(file.context_budget),
+            model_context_budgets: if self.model_context_budgets.is_empty() {
+                file.model_context_budgets
+            } else {
+                self.model_context_budgets
+            },
+            connect_timeout_secs: self.connect_timeout_secs.or(file.connect_timeout_secs),
+            request_timeout_secs: self.request_timeout_secs.or(file.request_timeout_secs),
+            token_idle_timeout_secs: self.token_idle_timeout_secs.or(file.token_idle_timeout_secs),
+            pool_max_idle_per_host: self.pool_max_idle_per_host.or(file.pool_max_idle_per_host),
+            pool_idle_timeout_secs: self.pool_idle_timeout_secs.or(file.pool_idle_timeout_secs),
+            tcp_keepalive_secs: self.tcp_keepalive_secs.or(file.tcp_keepalive_secs),
+            rate_limit: self.rate_limit.or(file.rate_limit),
+            session_rate_limit: self.session_rate_limit.or(file.session_rate_limit),
+            rate_limit_window_secs: self.rate_limit_window_secs.or(file.rate_limit_window_secs),
+            trust_forwarded_for: self.trust_forwarded_for || file.trust_forwarded_for,
+            api_keys: if self.api_keys.is_empty() { file.api_keys } else { self.api_keys },
+            allowed_models: if self.allowed_models.is_empty() { file.allowed_models } else { self.allowed_models },
+            cors_allowed_origins: if self.cors_allowed_origins.is_empty() {
+                file.cors_allowed_origins
+            } else {
+                self.cors_allowed_origins
+            },
+            connect_attempts: self.connect_attempts.or(file.connect_attempts),
+            retry_delay_ms: self.retry_delay_ms.or(file.retry_delay_ms),
+            stream_reconnects: self.stream_reconnects.or(file.stream_reconnects),
+            max_generations: self.max_generations.or(file.max_generations),
+            queue_timeout_ms: self.queue_timeout_ms.or(file.queue_timeout_ms),
+            workers: self.workers.or(file.workers),
+            queue_depth: self.queue_depth.or(file.queue_depth),
+            stream_buffer: self.stream_buffer.or(file.stream_buffer),
+            sse_keep_alive_secs: self.sse_keep_alive_secs.or(file.sse_keep_alive_secs),
+            max_prompt_chars: self.max_prompt_chars.or(file.max_prompt_chars),
+            static_dir: self.static_dir.or(file.static_dir),
+            tls_cert: self.tls_cert.or(file.tls_cert),
+            tls_key: self.tls_key.or(file.tls_key),
+            index_file: self.index_file.or(file.index_file),
+            forward_tool_calls: self.forward_tool_calls || file.forward_tool_calls,
+            mock_backend: self.mock_backend,
+            audit_log: self.audit_log || file.audit_log,
+            moderation_file: self.moderation_file.or(file.moderation_file),
+            moderation_redact: self.moderation_redact || file.moderation_redact,
+            session_ttl_secs: self.session_ttl_secs.or(file.session_ttl_secs),
+            max_sessions: self.max_sessions.or(file.max_sessions),
+            session_db: self.session_db.or(file.session_db),
+            response_cache_size: self.response_cache_size.or(file.response_cache_size),
+            response_cache_ttl_secs: self.response_cache_ttl_secs.or(file.response_cache_ttl_secs),
+            cleaning_rules: self.cleaning_rules.or(file.cleaning_rules),
+        }
+    }
+}
+
+/// Settings from the TOML file. The default path may be absent; an explicitly named one must exist.
+/// A file that doesn't parse aborts startup rather than silently running with defaults.
+fn read_file(explicit: Option<&Path>) -> Settings {
+    let path = explicit.unwrap_or(Path::new(DEFAULT_CONFIG_FILE));
+    let toml = match std::fs::read_to_string(path) {
+        Ok(toml) => toml,
+        Err(err) if explicit.is_none() && err.kind() == std::io::ErrorKind::NotFound => return Settings::default(),
+        Err(err) => panic!("failed to read config file {}: {}", path.display(), err),
+    };
+    let mut settings: Settings =
+        toml::from_str(&toml).unwrap_or_else(|err| panic!("invalid config file {}: {}", path.display(), err));
+    settings.config = Some(path.to_path_buf());
+    settings
+}
+
+/// Where the `openai` provider sends requests
+#[derive(Debug, Clone)]
+pub struct OpenAiUpstream {
+    /// Base URL without a trailing slash
+    pub url: String,
+    pub api_key: Option<String>,
+}
+
+/// Certificate and key to serve HTTPS with
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Resolved server configuration, shared with every handler
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The TOML file the settings were read from, if any
+    pub file: Option<PathBuf>,
+    pub log_format: LogFormat,
+    pub bind: SocketAddr,
+    pub model: String,
+    pub default_prompt: Option<String>,
+    /// Prompt templates by name
+    pub templates: HashMap<String, String>,
+    pub system_prompt: Option<String>,
+    pub system_prompt_file: Option<PathBuf>,
+    /// Ollama base URLs without a trailing slash; never empty
+    pub ollama_urls: Vec<String>,
+    pub balance_strategy: BalanceStrategy,
+    /// How long a host that couldn't be reached is routed around
+    pub upstream_cooldown: Duration,
+    /// The `openai` provider; `None` when not configured
+    pub openai: Option<OpenAiUpstream>,
+    /// Providers by model, in `normalize_model` form
+    pub model_providers: HashMap<String, Provider>,
+    /// Estimated tokens a conversation is trimmed to; 0 when history is sent whole
+    pub context_budget: usize,
+    /// Budgets by model, in `normalize_model` form
+    pub model_context_budgets: HashMap<String, usize>,
+    /// How long the HTTP client waits for a TCP connection to Ollama
+    pub connect_timeout: Duration,
+    /// Deadline for an entire chat call, including streaming the body
+    pub request_timeout: Duration,
+    /// `None` when only the request timeout bounds a stalled stream
+    pub token_idle_timeout: Option<Duration>,
+    pub pool_max_idle_per_host: usize,
+    /// `None` when idle connections are never closed
+    pub pool_idle_timeout: Option<Duration>,
+    /// `None` when keepalive probes are off
+    pub tcp_keepalive: Option<Duration>,
+    pub rate_limit: u32,
+    pub session_rate_limit: u32,
+    pub rate_limit_window: Duration,
+    pub trust_forwarded_for: bool,
+    pub api_keys: Vec<String>,
+    /// Allowed models in `normalize_model` form; empty allows any
+    pub allowed_models: Vec<String>,
+    /// Origins the CORS layer starts out allowing, trimmed; empty allows any
+    pub cors_allowed_origins: Vec<String>,
+    pub connect_attempts: u32,
+    pub retry_base_delay: Duration,
+    /// Reconnects allowed per deterministic stream after a mid-stream disconnect; 0 when disabled
+    pub stream_reconnects: u32,
+    pub max_generations: usize,
+    pub queue_timeout: Duration,
+    /// Size of the generation worker pool; 0 when every stream gets its own task
+    pub workers: usize,
+    /// Generations the worker pool's queue holds before rejecting more
+    pub queue_depth: usize,
+    /// Capacity of the per-stream token channel; trades latency under backpressure against memory
+    pub stream_buffer: usize,
+    /// Idle time after which SSE streams get a heartbeat comment; `None` when disabled
+    pub sse_keep_alive: Option<Duration>,
+    /// Size cap on incoming prompts, counted over all messages; 0 when unlimited
+    pub max_prompt_chars: usize,
+    pub static_dir: PathBuf,
+    /// Serve HTTPS instead of HTTP when set
+    pub tls: Option<TlsFiles>,
+    pub index_file: PathBuf,
+    pub forward_tool_calls: bool,
+    /// `mock_backend::MockBackend` in place of every real backend
+    pub mock_backend: bool,
+    pub audit_log: bool,
+    /// Blocklist for `hooks::Moderation`; moderation is off without one
+    pub moderation_file: Option<PathBuf>,
+    pub moderation_redact: bool,
+    pub session_ttl: Duration,
+    /// In-memory session capacity; `None` when unlimited
+    pub max_sessions: Option<NonZeroUsize>,
+    pub session_db: Option<PathBuf>,
+    /// Capacity of the response cache; `None` when it's disabled
+    pub response_cache_size: Option<NonZeroUsize>,
+    pub response_cache_ttl: Duration,
+    pub cleaning_rules: Option<PathBuf>,
+}
+
+impl Config {
+    /// Parse CLI flags and environment variables, then fill the gaps from the config file
+    pub fn load() -> Self {
+        Self::parse_from(std::env::args_os())
+    }
+
+    /// Like `load`, but with an explicit argument list (the first item is the binary name)
+    pub fn parse_from<I, T>(args: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let cli = Settings::parse_from(args);
+        let file = read_file(cli.config.as_deref());
+        let file_path = file.config.clone();
+        let settings = cli.or(file);
+
+        let ollama_url = settings
+            .ollama_url
+            .or_else(|| std::env::var("OLLAMA_HOST").ok())
+            .unwrap_or_else(|| DEFAULT_OLLAMA_URL.to_string());
+        let stream_buffer = settings.stream_buffer.unwrap_or(DEFAULT_STREAM_BUFFER).max(1);
+        let tls = match (settings.tls_cert, settings.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsFiles { cert, key }),
+            (None, None) => None,
+            _ => panic!("--tls-cert and --tls-key must be set together"),
+        };
+        if settings.mock_backend && !cfg!(debug_assertions) {
+            panic!("--mock-backend is only available in debug builds");
+        }
+
+        Self {
+            file: file_path,
+            log_format: settings.log_format.unwrap_or_else(|| {
+                if std::io::stdout().is_terminal() {
+                    LogFormat::Pretty
+                } else {
+                    LogFormat::Json
+                }
+            }),
+            bind: SocketAddr::new(
+                settings.addr.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+                settings.port.unwrap_or(DEFAULT_PORT),
+            ),
+            model: settings.model.unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+            default_prompt: settings.default_prompt,
+            templates: settings.templates,
+            system_prompt: settings.system_prompt,
+            system_prompt_file: settings.system_prompt_file,
+            ollama_urls: ollama_urls(&ollama_url),
+            balance_strategy: settings.balance_strategy.unwrap_or(BalanceStrategy::RoundRobin),
+            upstream_cooldown: Duration::from_secs(
+                settings.upstream_cooldown_secs.unwrap_or(DEFAULT_UPSTREAM_COOLDOWN_SECS),
+            ),
+            openai: (settings.openai_url.is_some() || settings.openai_api_key.is_some()).then(|| OpenAiUpstream {
+                url: normalize_base_url(settings.openai_url.as_deref().unwrap_or(DEFAULT_OPENAI_URL)),
+                api_key: settings.openai_api_key.filter(|key| !key.trim().is_empty()),
+            }),
+            model_providers: settings
+                .model_providers
+                .iter()
+                .filter(|route| !route.trim().is_empty())
+                .map(|route| {
+                    model_setting(route)
+                        .unwrap_or_else(|err| panic!("invalid --model-providers entry {:?}: {}", route, err))
+                })
+                .collect(),
+            context_budget: settings.context_budget.unwrap_or(0),
+            model_context_budgets: settings
+                .model_context_budgets
+                .iter()
+                .filter(|budget| !budget.trim().is_empty())
+                .map(|budget| {
+                    model_setting(budget)
+                        .unwrap_or_else(|err| panic!("invalid --model-context-budgets entry {:?}: {}", budget, err))
+                })
+                .collect(),
+            connect_timeout: Duration::from_secs(settings.connect_timeout_secs.unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS)),
+            request_timeout: Duration::from_secs(settings.request_timeout_secs.unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECS)),
+            token_idle_timeout: Some(settings.token_idle_timeout_secs.unwrap_or(DEFAULT_TOKEN_IDLE_TIMEOUT_SECS))
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            pool_max_idle_per_host: settings.pool_max_idle_per_host.unwrap_or(DEFAULT_POOL_MAX_IDLE_PER_HOST),
+            pool_idle_timeout: Some(settings.pool_idle_timeout_secs.unwrap_or(DEFAULT_POOL_IDLE_TIMEOUT_SECS))
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            tcp_keepalive: Some(settings.tcp_keepalive_secs.unwrap_or(DEFAULT_TCP_KEEPALIVE_SECS))
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            rate_limit: settings.rate_limit.unwrap_or(DEFAULT_RATE_LIMIT),
+            session_rate_limit: settings.session_rate_limit.unwrap_or(0),
+            rate_limit_window: Duration::from_secs(
+                settings.rate_limit_window_secs.unwrap_or(DEFAULT_RATE_LIMIT_WINDOW_SECS),
+            ),
+            trust_forwarded_for: settings.trust_forwarded_for,
+            api_keys: settings
+                .api_keys
+                .into_iter()
+                .map(|key| key.trim().to_string())
+                .filter(|key| !key.is_empty())
+                .collect(),
+            allowed_models: settings
+                .allowed_models
+                .iter()
+                .map(|model| normalize_model(model))
+                .filter(|model| !model.is_empty())
+                .collect(),
+            cors_allowed_origins: settings
+                .cors_allowed_origins
+                .into_iter()
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+            connect_attempts: settings.connect_attempts.unwrap_or(DEFAULT_CONNECT_ATTEMPTS).max(1),
+            retry_base_delay: Duration::from_millis(settings.retry_delay_ms.unwrap_or(DEFAULT_RETRY_DELAY_MS)),
+            stream_reconnects: settings.stream_reconnects.unwrap_or(0),
+            max_generations: settings.max_generations.unwrap_or(0),
+            queue_timeout: Duration::from_millis(settings.queue_timeout_ms.unwrap_or(DEFAULT_QUEUE_TIMEOUT_MS)),
+            workers: settings.workers.unwrap_or(0),
+            queue_depth: settings.queue_depth.map_or(DEFAULT_WORKER_QUEUE_DEPTH, |depth| depth as usize),
+            stream_buffer: stream_buffer as usize,
+            sse_keep_alive: Some(settings.sse_keep_alive_secs.unwrap_or(DEFAULT_SSE_KEEP_ALIVE_SECS))
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            max_prompt_chars: settings.max_prompt_chars.unwrap_or(DEFAULT_MAX_PROMPT_CHARS),
+            static_dir: settings.static_dir.unwrap_or_else(|| PathBuf::from(DEFAULT_STATIC_DIR)),
+            tls,
+            index_file: settings.index_file.unwrap_or_else(|| PathBuf::from(DEFAULT_INDEX_FILE)),
+            forward_tool_calls: settings.forward_tool_calls,
+            mock_backend: settings.mock_backend,
+            audit_log: settings.audit_log,
+            moderation_file: settings.moderation_file,
+            moderation_redact: settings.moderation_redact,
+            session_ttl: Duration::from_secs(settings.session_ttl_secs.unwrap_or(DEFAULT_SESSION_TTL_SECS)),
+            max_sessions: NonZeroUsize::new(settings.max_sessions.unwrap_or(DEFAULT_MAX_SESSIONS)),
+            session_db: settings.session_db,
+            response_cache_size: NonZeroUsize::new(settings.response_cache_size.unwrap_or(0)),
+            response_cache_ttl: Duration::from_secs(
+                settings.response_cache_ttl_secs.unwrap_or(DEFAULT_RESPONSE_CACHE_TTL_SECS),
+            ),
+            cleaning_rules: settings.cleaning_rules,
+        }
+    }
+
+    /// The provider for `model`: `requested` when the request picked one, else its `--model-providers` route
+    pub fn provider_for(&self, model: &str, requested: Option<Provider>) -> Provider {
+        requested.or_else(|| self.model_providers.get(&normalize_model(model)).copied()).unwrap_or(Provider::Ollama)
+    }
+
+    /// The context budget for `model`: its `--model-context-budgets` entry, else `--context-budget`
+    pub fn context_budget_for(&self, model: &str) -> usize {
+        self.model_context_budgets.get(&normalize_model(model)).copied().unwrap_or(self.context_budget)
+    }
+
+    /// Whether requests may use `model` (always, without `--allowed-models`)
+    pub fn allows_model(&self, model: &str) -> bool {
+        self.allowed_models.is_empty() || self.allowed_models.contains(&normalize_model(model))
+    }
+}
+
+/// Lowercase, without the implied `:latest` tag, so `Mistral` and `mistral:latest` compare equal
+fn normalize_model(model: &str) -> String {
+    let model = model.trim().to_lowercase();
+    match model.strip_suffix(":latest") {
+        Some(name) => name.to_string(),
+        None => model,
+    }
+}
+
+/// One `model=value` entry of a per-model setting such as `--model-providers`
+fn model_setting<T>(entry: &str) -> Result<(String, T), String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let (model, value) = entry.split_once('=').ok_or("expected `model=value`")?;
+    let model = normalize_model(model);
+    if model.is_empty() {
+        return Err("missing model name".to_string());
+    }
+    Ok((model, value.trim().parse().map_err(|err: T::Err| err.to_string())?))
+}
+
+/// Every URL in a comma-separated `--ollama-url`, normalized; a blank list means the default
+fn ollama_urls(raw: &str) -> Vec<String> {
+    let urls: Vec<String> =
+        raw.split(',').filter(|url| !url.trim().is_empty()).map(normalize_base_url).collect();
+    if urls.is_empty() {
+        vec![DEFAULT_OLLAMA_URL.to_string()]
+    } else {
+        urls
+    }
+}
+
+/// Strip trailing slashes and default the scheme, so `host:11434`, `http://host:11434` and `http://host:11434/` all work
+fn normalize_base_url(raw: &str) -> String {
+    let trimmed = raw.trim().trim_end_matches('/');
+    if trimmed.contains("://") {
+        trimmed.to_string()
+    } else {
+        format!("http://{}", trimmed)
+    }
+}
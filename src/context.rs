@@ -0,0 +1,85 @@
+use crate::Message;
+
+/// English text averages about four characters per token across common tokenizers
+const CHARS_PER_TOKEN: usize = 4;
+/// Role markers and separators the chat template wraps each message in
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+/// What an attached image costs vision models such as LLaVA, roughly
+const IMAGE_TOKENS: usize = 768;
+
+/// A rough token count for `message`, rounded up so an estimate that fits doesn't overflow in practice
+pub fn estimate_tokens(message: &Message) -> usize {
+    MESSAGE_OVERHEAD_TOKENS
+        + message.content.chars().count().div_ceil(CHARS_PER_TOKEN)
+        + message.images.len() * IMAGE_TOKENS
+}
+
+/// Drop the oldest messages until `messages` fit in `budget` estimated tokens, returning how many went.
+/// System messages and the latest user message always stay, even when they alone are over budget.
+pub fn trim_to_budget(messages: &mut Vec<Message>, budget: usize) -> usize {
+    let mut total: usize = messages.iter().map(estimate_tokens).sum();
+    let latest_user = messages.iter().rposition(|message| message.role == "user");
+    let mut keep = vec![true; messages.len()];
+    for (index, message) in messages.iter().enumerate() {
+        if total <= budget {
+            break;
+        }
+        if message.role == "system" || Some(index) == latest_user {
+            continue;
+        }
+        keep[index] = false;
+        total -= estimate_tokens(message);
+    }
+
+    let before = messages.len();
+    let mut keep = keep.into_iter();
+    messages.retain(|_| keep.next().unwrap_or(true));
+    before - messages.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message { role: role.to_string(), content: content.to_string(), images: Vec::new() }
+    }
+
+    #[test]
+    fn history_is_trimmed_oldest_first_keeping_system_and_latest_user() {
+        let long = "x".repeat(400);
+        let mut messages = vec![
+            message("system", "Be nice"),
+            message("user", &long),
+            message("assistant", &long),
+            message("user", "short"),
+            message("assistant", "ok"),
+            message("user", &long),
+        ];
+        // The system prompt, the latest question and one short turn fit in 125; the first turn doesn't
+        assert_eq!(trim_to_budget(&mut messages, 125), 2);
+        let roles: Vec<_> = messages.iter().map(|message| message.role.as_str()).collect();
+        assert_eq!(roles, ["system", "user", "assistant", "user"]);
+        assert_eq!(messages[3].content, long);
+
+        // Over budget on their own, the system prompt and latest question still go out
+        assert_eq!(trim_to_budget(&mut messages, 1), 2);
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[test]
+    fn models_can_have_their_own_budget() {
+        let config = Config::parse_from([
+            "chatbot_api",
+            "--context-budget",
+            "4000",
+            "--model-context-budgets",
+            "Llama3=8000,phi3:latest=2000",
+        ]);
+        assert_eq!(config.context_budget_for("llama3"), 8000);
+        assert_eq!(config.context_budget_for("phi3"), 2000);
+        assert_eq!(config.context_budget_for("PHI3:latest"), 2000);
+        assert_eq!(config.context_budget_for("mistral"), 4000);
+    }
+}
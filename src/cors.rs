@@ -0,0 +1,134 @@
+use std::sync::RwLock;
+
+use axum::{
+    extract::{Json, State},
+    http::{header, HeaderValue, Method, Uri},
+};
+use serde::{Deserialize, Serialize};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{info, warn};
+
+use crate::{config::Config, AppError, AppState, JsonBody};
+
+/// The origins browsers may call from, changeable at runtime through `PUT /admin/cors`.
+/// `None` allows any origin.
+#[derive(Debug)]
+pub struct CorsOrigins {
+    origins: RwLock<Option<Vec<HeaderValue>>>,
+}
+
+impl CorsOrigins {
+    /// Any origin, unless `config.cors_allowed_origins` lists them
+    pub fn new(config: &Config) -> Self {
+        let origins = (!config.cors_allowed_origins.is_empty()).then(|| {
+            let list: Vec<_> = config
+                .cors_allowed_origins
+                .iter()
+                .filter_map(|origin| match parse_origin(origin) {
+                    Ok(value) => Some(value),
+                    Err(err) => {
+                        warn!("Ignoring CORS origin: {}", err);
+                        None
+                    }
+                })
+                .collect();
+            info!("🌐 CORS restricted to {} origin(s)", list.len());
+            list
+        });
+        Self { origins: RwLock::new(origins) }
+    }
+
+    fn allows(&self, origin: &HeaderValue) -> bool {
+        match &*self.origins.read().unwrap() {
+            Some(list) => list.contains(origin),
+            None => true,
+        }
+    }
+
+    fn snapshot(&self) -> Option<Vec<String>> {
+        let origins = self.origins.read().unwrap();
+        origins.as_ref().map(|list| list.iter().filter_map(|origin| origin.to_str().ok()).map(str::to_string).collect())
+    }
+
+    fn replace(&self, origins: Option<Vec<HeaderValue>>) {
+        *self.origins.write().unwrap() = origins;
+    }
+}
+
+/// CORS policy for browser clients served from another origin.
+///
+/// - Origins: whatever `origins` holds when the request arrives
+/// - Methods: `GET`, `POST`, `PUT` (preflight `OPTIONS` requests are answered by the layer itself)
+/// - Headers: `Content-Type`, `Authorization`
+pub fn cors_layer(state: &AppState) -> CorsLayer {
+    let origins = state.cors_origins.clone();
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(move |origin, _| origins.allows(origin)))
+        .allow_methods(vec![Method::GET, Method::POST, Method::PUT])
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
+}
+
+/// An origin as browsers send it: `http` or `https`, a host, an optional port and nothing else.
+/// A single trailing `/` is forgiven, since that's how origins tend to get copied from an address bar.
+fn parse_origin(origin: &str) -> Result<HeaderValue, String> {
+    let trimmed = origin.strip_suffix('/').unwrap_or(origin);
+    let invalid = |reason: &str| format!("{:?} is not a valid origin: {}", origin, reason);
+    let uri: Uri = trimmed.parse().map_err(|_| invalid("not a URL"))?;
+    if !matches!(uri.scheme_str(), Some("http" | "https")) {
+        return Err(invalid("expected an http:// or https:// URL"));
+    }
+    match uri.authority() {
+        Some(authority) if !authority.host().is_empty() && !authority.as_str().contains('@') => {}
+        _ => return Err(invalid("missing host")),
+    }
+    if !matches!(uri.path(), "" | "/") || uri.query().is_some() {
+        return Err(invalid("origins have no path or query"));
+    }
+    HeaderValue::from_str(trimmed).map_err(|_| invalid("not a valid header value"))
+}
+
+/// Body of `PUT /admin/cors`; `["*"]` allows any origin again
+#[derive(Debug, Deserialize)]
+pub struct CorsUpdate {
+    origins: Vec<String>,
+}
+
+/// The origins now in effect; `"*"` when any is allowed
+#[derive(Debug, Serialize)]
+pub struct CorsStatus {
+    origins: serde_json::Value,
+}
+
+impl CorsStatus {
+    fn of(origins: &CorsOrigins) -> Self {
+        Self { origins: origins.snapshot().map_or_else(|| "*".into(), Into::into) }
+    }
+}
+
+/// `GET /admin/cors`
+pub async fn get_cors_handler(State(state): State<AppState>) -> Json<CorsStatus> {
+    Json(CorsStatus::of(&state.cors_origins))
+}
+
+/// `PUT /admin/cors` with `{"origins": ["https://chat.example.com", ...]}`.
+/// All origins are checked before any takes effect, so a typo leaves the current list in place.
+pub async fn put_cors_handler(
+    State(state): State<AppState>,
+    JsonBody(update): JsonBody<CorsUpdate>,
+) -> Result<Json<CorsStatus>, AppError> {
+    let origins = if update.origins.iter().any(|origin| origin.trim() == "*") {
+        if update.origins.len() > 1 {
+            return Err(AppError::bad_request("\"*\" can't be combined with other origins"));
+        }
+        None
+    } else {
+        let list = update.origins.iter().map(|origin| parse_origin(origin.trim())).collect::<Result<Vec<_>, _>>();
+        Some(list.map_err(AppError::BadRequest)?)
+    };
+    match &origins {
+        Some(list) => info!("🌐 CORS origins updated, {} allowed", list.len()),
+        None => info!("🌐 CORS origins updated, any allowed"),
+    }
+    state.cors_origins.replace(origins);
+    Ok(Json(CorsStatus::of(&state.cors_origins)))
+}
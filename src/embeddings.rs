@@ -0,0 +1,71 @@
+use axum::extract::{Json, State};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{backend::upstream_error, validate_model, AppError, AppState, JsonBody};
+
+/// Body of `POST /embeddings`, forwarded to Ollama's `/api/embed` as is
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EmbeddingsRequest {
+    /// Empty means "use the configured default model"
+    #[serde(default)]
+    model: String,
+    input: EmbeddingInput,
+}
+
+/// One text, or several embedded in a single call
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Ollama's answer, and ours: one vector per input, in order
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Embeddings {
+    #[serde(default)]
+    model: String,
+    embeddings: Vec<Vec<f32>>,
+}
+
+/// `POST /embeddings` with `{"model": ..., "input": "text" | ["text", ...]}`
+pub async fn embeddings_handler(
+    State(state): State<AppState>,
+    JsonBody(mut request): JsonBody<EmbeddingsRequest>,
+) -> Result<Json<Embeddings>, AppError> {
+    if request.model.is_empty() {
+        request.model = state.config.model.clone();
+    }
+    validate_model(&state.config, &request.model)?;
+    if matches!(&request.input, EmbeddingInput::Many(inputs) if inputs.is_empty()) {
+        return Err(AppError::bad_request("input must not be empty"));
+    }
+
+    let timeout = state.config.request_timeout;
+    let upstream = state.upstreams.pick();
+    let response = state
+        .client
+        .post(upstream.endpoint("/api/embed"))
+        .timeout(timeout)
+        .json(&request)
+        .send()
+        .await
+        .map_err(|err| {
+            warn!(error = ?err, host = upstream.url(), "Error fetching embeddings");
+            if err.is_connect() {
+                upstream.mark_unhealthy();
+            }
+            AppError::from_reqwest(&err, timeout)
+        })?;
+    if !response.status().is_success() {
+        let error = upstream_error(response).await;
+        warn!(%error, "Ollama rejected the embeddings request");
+        return Err(error);
+    }
+    let mut embeddings: Embeddings = response.json().await.map_err(|err| AppError::from_reqwest(&err, timeout))?;
+    if embeddings.model.is_empty() {
+        embeddings.model = request.model;
+    }
+    Ok(Json(embeddings))
+}
@@ -0,0 +1,138 @@
+use std::{borrow::Cow, fmt, sync::Arc};
+
+use regex::{Regex, RegexSet};
+use tracing::{info, warn};
+
+use crate::{AppError, Message};
+
+/// An extension point around every generation, for moderation, auditing, PII redaction and the like.
+///
+/// Hooks run synchronously, in the order they were added. `on_token` runs for every token of every
+/// stream, so it has to be cheap and must never block.
+pub trait ChatHook: Send + Sync {
+    /// Short name for logs
+    fn name(&self) -> &'static str;
+
+    /// The conversation about to be sent, system prompt included. Rewriting it changes what the
+    /// model sees, not the session's stored history; an `Err` rejects the request with that error.
+    fn before_request(&self, _messages: &mut Vec<Message>) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    /// Each cleaned token on its way to the client (and into the session); `None` drops it
+    fn on_token(&self, token: String) -> Option<String> {
+        Some(token)
+    }
+}
+
+/// The hooks registered at startup, applied by `chat_stream` and the relay
+#[derive(Clone, Default)]
+pub struct Hooks {
+    hooks: Vec<Arc<dyn ChatHook>>,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+impl Hooks {
+    pub fn with(mut self, hook: impl ChatHook + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.hooks.iter().map(|hook| hook.name())
+    }
+
+    /// Every hook's `before_request`, stopping at the first rejection
+    pub fn before_request(&self, messages: &mut Vec<Message>) -> Result<(), AppError> {
+        self.hooks.iter().try_for_each(|hook| hook.before_request(messages))
+    }
+
+    /// Every hook's `on_token`, stopping once one drops the token
+    pub fn on_token(&self, token: String) -> Option<String> {
+        self.hooks.iter().try_fold(token, |token, hook| hook.on_token(token))
+    }
+}
+
+/// `--audit-log`: one line per prompt under the `audit` target, with its shape but not its content
+#[derive(Debug)]
+pub struct AuditLog;
+
+impl ChatHook for AuditLog {
+    fn name(&self) -> &'static str {
+        "audit_log"
+    }
+
+    fn before_request(&self, messages: &mut Vec<Message>) -> Result<(), AppError> {
+        let roles: Vec<&str> = messages.iter().map(|message| message.role.as_str()).collect();
+        let chars: usize = messages.iter().map(|message| message.content.chars().count()).sum();
+        let images: usize = messages.iter().map(|message| message.images.len()).sum();
+        info!(target: "audit", messages = messages.len(), roles = %roles.join(","), chars, images, "Prompt sent");
+        Ok(())
+    }
+}
+
+/// What a redacted match is replaced with in streamed replies
+const REDACTED: &str = "[redacted]";
+
+/// `--moderation-file`: reject prompts matching a blocklist of regex patterns and, with
+/// `--moderation-redact`, blank the matches out of replies.
+///
+/// Replies are redacted token by token, like the cleaning rules, so a match the model splits
+/// across tokens gets through; patterns meant for output should target whole words.
+#[derive(Debug)]
+pub struct Moderation {
+    blocklist: RegexSet,
+    /// The same patterns one by one, for redacting; empty unless `--moderation-redact`
+    redactions: Vec<Regex>,
+}
+
+impl Moderation {
+    /// One pattern per line; blank lines and lines starting with `#` are skipped
+    pub fn parse(list: &str, redact: bool) -> Result<Self, String> {
+        let mut patterns = Vec::new();
+        for (index, line) in list.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            patterns.push(Regex::new(line).map_err(|err| format!("line {}: {}", index + 1, err))?);
+        }
+        let blocklist = RegexSet::new(patterns.iter().map(Regex::as_str)).map_err(|err| err.to_string())?;
+        let redactions = if redact { patterns } else { Vec::new() };
+        Ok(Self { blocklist, redactions })
+    }
+}
+
+impl ChatHook for Moderation {
+    fn name(&self) -> &'static str {
+        "moderation"
+    }
+
+    /// Only what users wrote: the system prompt is the operator's, and replies are redacted instead
+    fn before_request(&self, messages: &mut Vec<Message>) -> Result<(), AppError> {
+        let blocked = messages.iter().filter(|message| message.role == "user").find_map(|message| {
+            self.blocklist.matches(&message.content).iter().next()
+        });
+        match blocked {
+            Some(pattern) => {
+                // The pattern stays out of the response, so it can't be probed for
+                warn!(target: "audit", pattern = %self.blocklist.patterns()[pattern], "Prompt blocked by moderation");
+                Err(AppError::bad_request("the prompt was blocked by the content policy"))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn on_token(&self, token: String) -> Option<String> {
+        let token = self.redactions.iter().fold(token, |token, pattern| match pattern.replace_all(&token, REDACTED) {
+            Cow::Owned(redacted) => redacted,
+            Cow::Borrowed(_) => token,
+        });
+        Some(token)
+    }
+}
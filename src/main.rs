@@ -1,48 +1,399 @@
+mod abort;
+mod auth;
+mod backend;
+mod cache;
+mod clean;
+mod config;
+mod context;
+mod cors;
+mod embeddings;
+mod hooks;
+mod metrics;
+mod mock_backend;
+mod openai;
+mod openai_backend;
+mod persist;
+mod pool;
+mod rate_limit;
+mod regenerate;
+mod session;
+mod stop;
+mod template;
+mod title;
+mod tools;
+mod upstream;
+mod ws;
+
 use axum::{
-    routing::get,
+    handler::HandlerWithoutStateExt,
+    middleware,
+    routing::{get, post},
     Router,
-    extract::Query,
-    response::{Html, IntoResponse, Response},
-    http::{header, StatusCode, Method},  // Use `http::Method` here
+    extract::{rejection::JsonRejection, FromRequest, FromRequestParts, Json, State},
+    response::{sse::{Event, KeepAlive as SseKeepAlive, Sse}, AppendHeaders, Html, IntoResponse, Response},
+    http::{header, StatusCode},
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
-use std::{collections::HashMap, pin::Pin, net::SocketAddr};
-use futures::{Stream, StreamExt};
+use futures::{future, Future, FutureExt, Stream, StreamExt};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use serde::{Deserialize, Serialize};
-use tower_http::cors::{CorsLayer, Any};
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    compression::CompressionLayer,
+    services::ServeDir,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::{DefaultOnResponse, TraceLayer},
+};
+use tracing::{debug, error, info, warn, Instrument, Level};
 use tokio::net::TcpListener;
+use axum_server::tls_rustls::RustlsConfig;
 use reqwest::Client;
 use tokio_stream::wrappers::ReceiverStream;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
 use regex::Regex;
 use once_cell::sync::Lazy;
 use tokio::fs;
+use backend::{ChatBackend, Chunk, ChunkStream, OllamaBackend};
+use openai_backend::OpenAiBackend;
+use mock_backend::MockBackend;
+use cache::ResponseCache;
+use clean::{clean_content, CleanState, CleaningRules, ModelRules};
+use clap::ValueEnum;
+use config::{Config, LogFormat, Provider};
+use cors::CorsOrigins;
+use hooks::{AuditLog, Hooks, Moderation};
+use metrics::Metrics;
+use rate_limit::{ClientKey, RateLimiter};
+use abort::InFlight;
+use persist::ConversationDb;
+use pool::WorkerPool;
+use session::{SessionHandle, SessionStore};
+use stop::StopSequences;
+use tools::ToolCallParser;
+use upstream::Upstreams;
 
 /// Precompile regex for efficiency
-static CONTROL_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\[control_\d+\]").unwrap());
-static UNKNOWN_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(<unk>|<unk>)").unwrap());
-static TOOL_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\[TOOL_CALLS\]|\[TOOL_RESULTS\])").unwrap());
-static MULTI_SPACE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\s+").unwrap());
+/// Ollama model names look like `mistral`, `llama3.1:8b` or `library/qwen2:7b-instruct`
+static MODEL_NAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[A-Za-z0-9][A-Za-z0-9._:/-]{0,127}$").unwrap());
+/// Go-style durations as Ollama parses them: `5m`, `1h30m`, `-1`, `0`
+static KEEP_ALIVE_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^-?(\d+(\.\d+)?(ns|us|µs|ms|s|m|h)?)+$").unwrap());
+
+const MAX_SESSION_ID_LEN: usize = 128;
+/// `tool` carries a tool call's result back to the model, in Ollama's format and OpenAI's alike
+const MESSAGE_ROLES: &[&str] = &["system", "user", "assistant", "tool"];
+/// Readiness probes must answer quickly even when Ollama is wedged
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Correlates a request's log lines; taken from the caller or generated as a UUID v4
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const RESPONSE_TIME_HEADER: &str = "x-response-time";
+/// Suggested wait before retrying when every generation slot is taken
+const OVERLOADED_RETRY_AFTER_SECS: u64 = 2;
 
 /// Structs for request & response handling
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct ChatRequest {
+    /// Empty means "use the configured default model"
+    #[serde(default)]
     model: String,
     messages: Vec<Message>,
+    /// `false` asks `/chat` for one JSON object instead of a token stream; Ollama itself is always streamed
+    #[serde(default = "default_stream")]
     stream: bool,
+    /// Prepended as a `system` message before sending; never forwarded as-is
+    #[serde(default, skip_serializing)]
+    system: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    options: Option<ChatOptions>,
+    /// How long Ollama keeps the model loaded after this request; unset leaves Ollama's default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<KeepAlive>,
+    /// Constrains the reply to JSON, or to a given JSON schema
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    format: Option<OutputFormat>,
+    /// Backend to use instead of the model's `--model-providers` route; never forwarded
+    #[serde(default, skip_serializing)]
+    provider: Option<Provider>,
 }
 
+/// Body of `POST /generate`, a bare completion through Ollama's `/api/generate`
 #[derive(Debug, Deserialize, Serialize)]
+struct GenerateRequest {
+    /// Empty means "use the configured default model"
+    #[serde(default)]
+    model: String,
+    prompt: String,
+    #[serde(default = "default_stream")]
+    stream: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    options: Option<ChatOptions>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<KeepAlive>,
+    #[serde(default, skip_serializing)]
+    provider: Option<Provider>,
+}
+
+/// Ollama's `keep_alive`: seconds (negative keeps the model loaded indefinitely) or a duration string
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+enum KeepAlive {
+    Seconds(i64),
+    Duration(String),
+}
+
+impl std::str::FromStr for KeepAlive {
+    type Err = std::convert::Infallible;
+
+    /// Bare integers from a query string are sent as numbers, everything else as a duration
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        Ok(raw.parse().map(KeepAlive::Seconds).unwrap_or_else(|_| KeepAlive::Duration(raw.to_string())))
+    }
+}
+
+impl KeepAlive {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            KeepAlive::Duration(raw) if !KEEP_ALIVE_REGEX.is_match(raw) => {
+                Err(format!("invalid keep_alive: {:?} (expected e.g. \"5m\", \"1h\" or -1)", raw))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Ollama's `format`: `"json"` for any valid JSON, or an inline JSON schema the reply must match
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged, try_from = "serde_json::Value")]
+enum OutputFormat {
+    Named(String),
+    Schema(serde_json::Map<String, serde_json::Value>),
+}
+
+impl TryFrom<serde_json::Value> for OutputFormat {
+    type Error = &'static str;
+
+    /// Spelled out so a wrong type says what's expected, rather than serde's "no variant matched"
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        match value {
+            serde_json::Value::String(name) => Ok(OutputFormat::Named(name)),
+            serde_json::Value::Object(schema) => Ok(OutputFormat::Schema(schema)),
+            _ => Err("expected \"json\" or a JSON schema object"),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Ollama answers a malformed schema with a bare 500, so catch the obvious mistakes here
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            OutputFormat::Named(name) if name == "json" => Ok(()),
+            OutputFormat::Named(name) => Err(format!("invalid format: {:?} (expected \"json\" or a JSON schema)", name)),
+            OutputFormat::Schema(schema) => validate_schema("format", schema),
+        }
+    }
+}
+
+/// Check the shape of the schema keywords Ollama relies on, in `schema` and every nested property
+fn validate_schema(path: &str, schema: &serde_json::Map<String, serde_json::Value>) -> Result<(), String> {
+    use serde_json::Value;
+
+    match schema.get("type") {
+        None | Some(Value::String(_)) => {}
+        Some(Value::Array(types)) if types.iter().all(Value::is_string) => {}
+        Some(_) => return Err(format!("{}.type must be a string or an array of strings", path)),
+    }
+    match schema.get("required") {
+        None => {}
+        Some(Value::Array(names)) if names.iter().all(Value::is_string) => {}
+        Some(_) => return Err(format!("{}.required must be an array of strings", path)),
+    }
+    match schema.get("properties") {
+        None => {}
+        Some(Value::Object(properties)) => {
+            for (name, property) in properties {
+                let Value::Object(property) = property else {
+                    return Err(format!("{}.properties.{} must be a schema object", path, name));
+                };
+                validate_schema(&format!("{}.properties.{}", path, name), property)?;
+            }
+        }
+        Some(_) => return Err(format!("{}.properties must be an object", path)),
+    }
+    match schema.get("items") {
+        None => Ok(()),
+        Some(Value::Object(items)) => validate_schema(&format!("{}.items", path), items),
+        Some(_) => Err(format!("{}.items must be a schema object", path)),
+    }
+}
+
+/// Sampling parameters forwarded as Ollama's `options` object; unset fields keep the model defaults
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct ChatOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    /// Context window in tokens, for models run with a larger one than their Modelfile sets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    num_ctx: Option<u32>,
+    /// Upper bound on generated tokens; `max_tokens` on the handlers
+    #[serde(default, alias = "max_tokens", skip_serializing_if = "Option::is_none")]
+    num_predict: Option<u32>,
+    /// Fixed sampling seed; with temperature 0, the same prompt and seed give the same answer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    /// Sequences that end the generation; also enforced in `relay`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+}
+
+impl ChatOptions {
+    fn from_params(params: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        let options = ChatOptions {
+            temperature: parse_param(params, "temperature")?,
+            top_p: parse_param(params, "top_p")?,
+            top_k: parse_param(params, "top_k")?,
+            num_ctx: parse_param(params, "num_ctx")?,
+            num_predict: parse_param(params, "max_tokens")?,
+            seed: parse_param(params, "seed")?,
+            stop: params.get("stop").map(|raw| parse_stop(raw)),
+        };
+        Ok((!options.is_empty()).then_some(options))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.temperature.is_none()
+            && self.top_p.is_none()
+            && self.top_k.is_none()
+            && self.num_ctx.is_none()
+            && self.num_predict.is_none()
+            && self.seed.is_none()
+            && self.stop.is_none()
+    }
+
+    /// Whether the same request always gets the same reply: greedy sampling or a fixed seed
+    fn is_deterministic(&self) -> bool {
+        self.temperature == Some(0.0) || self.seed.is_some()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                return Err(format!("temperature must be between 0 and 2, got {}", temperature));
+            }
+        }
+        if let Some(top_p) = self.top_p {
+            if !(0.0..=1.0).contains(&top_p) {
+                return Err(format!("top_p must be between 0 and 1, got {}", top_p));
+            }
+        }
+        if self.top_k == Some(0) {
+            return Err("top_k must be at least 1".to_string());
+        }
+        if self.num_ctx == Some(0) {
+            return Err("num_ctx must be at least 1".to_string());
+        }
+        if self.num_predict == Some(0) {
+            return Err("max_tokens must be at least 1".to_string());
+        }
+        if self.stop.iter().flatten().any(String::is_empty) {
+            return Err("stop sequences must not be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// `stop` as a query parameter: a JSON array of strings, or else a single sequence
+fn parse_stop(raw: &str) -> Vec<String> {
+    serde_json::from_str(raw).unwrap_or_else(|_| vec![raw.to_string()])
+}
+
+fn default_stream() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct Message {
     role: String,
     content: String,
+    /// Base64-encoded images for vision models, forwarded to Ollama as they are
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
 }
 
+/// One NDJSON line of Ollama's chat stream. Everything but `done` is optional because
+/// the final line's shape (and which stats it carries) differs across Ollama versions.
 #[derive(Debug, Deserialize, Serialize)]
 struct ChatStreamResponse {
+    #[serde(default)]
     model: String,
+    #[serde(default)]
     created_at: String,
     message: Option<ChatMessage>,
+    /// Where `/api/generate` puts the text instead of `message`
+    response: Option<String>,
+    #[serde(default)]
     done: bool,
+    /// Why generation stopped (`stop`, `length`, ...), reported on the final line by newer Ollama versions
+    done_reason: Option<String>,
+    /// Generated tokens, reported on the final line
+    eval_count: Option<u64>,
+    /// Prompt tokens, reported on the final line
+    prompt_eval_count: Option<u64>,
+    /// Nanoseconds spent generating
+    eval_duration: Option<u64>,
+    /// Nanoseconds for the whole request, including model load
+    total_duration: Option<u64>,
+}
+
+/// Token usage and timing, sent to SSE clients when a generation finishes
+#[derive(Debug, Clone, Default, Serialize)]
+struct Usage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    completion_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tokens_per_second: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_duration_ms: Option<u64>,
+    /// Ollama's `done_reason`, so clients can tell a natural stop from a length cutoff
+    #[serde(skip_serializing_if = "Option::is_none")]
+    done_reason: Option<String>,
+}
+
+impl ChatStreamResponse {
+    /// This line's piece of the answer, whichever endpoint it came from
+    fn content(&self) -> Option<&str> {
+        self.message.as_ref().map(|message| message.content.as_str()).or(self.response.as_deref())
+    }
+}
+
+impl Usage {
+    fn from_final_line(line: &ChatStreamResponse) -> Self {
+        let tokens_per_second = match (line.eval_count, line.eval_duration) {
+            (Some(count), Some(nanos)) if nanos > 0 => Some(count as f64 / (nanos as f64 / 1e9)),
+            _ => None,
+        };
+        Self {
+            prompt_tokens: line.prompt_eval_count,
+            completion_tokens: line.eval_count,
+            tokens_per_second,
+            total_duration_ms: line.total_duration.map(|nanos| nanos / 1_000_000),
+            done_reason: line.done_reason.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -51,99 +402,2608 @@ struct ChatMessage {
     content: String,
 }
 
+/// Response of Ollama's `/api/tags`
+#[derive(Debug, Deserialize)]
+struct TagsResponse {
+    models: Vec<ModelInfo>,
+}
+
+/// One installed model, as listed by `/api/tags` and returned by `/models`
+#[derive(Debug, Deserialize, Serialize)]
+struct ModelInfo {
+    name: String,
+    size: u64,
+}
+
+/// One item relayed from the spawned Ollama task to a response body
+#[derive(Debug)]
+enum StreamEvent {
+    /// A cleaned chunk of assistant content
+    Token(String),
+    /// Ollama reported `done: true`, along with whatever stats it included
+    Done(Usage),
+    /// Tool calls parsed out of the content (with `--forward-tool-calls`), as a JSON array
+    ToolCalls(serde_json::Value),
+    /// The stream is ending early; always the last event
+    Error(AppError),
+}
+
+/// Everything that can go wrong serving a request. Clients get it as `{"error": {"code": ..., "message": ...}}`:
+/// as the response itself when nothing was sent yet, else as the last frame of the stream.
+#[derive(Debug, Clone)]
+enum AppError {
+    /// The request is malformed or out of range (400)
+    BadRequest(String),
+    /// Missing or unknown API key (401)
+    Unauthorized(String),
+    /// The route exists but is switched off, e.g. admin routes without `API_KEYS` (403)
+    Forbidden(String),
+    /// The request names something, such as a session, that doesn't exist (404)
+    NotFound(String),
+    /// The route exists but not for this method (405)
+    MethodNotAllowed,
+    /// The prompt is longer than `--max-prompt-chars` (413)
+    PayloadTooLarge { chars: usize, limit: usize },
+    /// The client used up its rate limit; `retry_after` is in whole seconds, `scope` says which limit
+    /// it hit, e.g. `IP address` or `session` (429)
+    RateLimited { retry_after: u64, scope: &'static str },
+    /// Every generation slot stayed busy for the whole queue timeout (503)
+    Overloaded,
+    /// Ollama couldn't be reached, or the connection to it failed (502)
+    UpstreamUnreachable(String),
+    /// Ollama answered with an error status, passed on as is; `detail` is its `error` message
+    UpstreamStatus { status: StatusCode, detail: Option<String> },
+    /// Ollama didn't finish within `--request-timeout-secs` (504)
+    Timeout(Duration),
+    /// The answer had started but no token came for `--token-idle-timeout-secs` (504)
+    Stalled(Duration),
+    /// The connection to Ollama broke after the answer had started (502)
+    ConnectionLost(String),
+    /// Ollama's response couldn't be decoded (502)
+    Parse(String),
+    /// The client disconnected; only ever logged, since there's nobody left to tell (499)
+    ClientGone,
+    /// A bug or an environment problem on our side (500)
+    Internal(String),
+    /// Anything else, with a status of its own: one of axum's rejections (e.g. 413 for an oversized body),
+    /// or a failed readiness probe (503)
+    Other { status: StatusCode, message: String },
+}
+
+impl AppError {
+    fn from_reqwest(err: &reqwest::Error, request_timeout: Duration) -> Self {
+        if err.is_timeout() {
+            Self::Timeout(request_timeout)
+        } else if err.is_decode() {
+            Self::Parse(err.to_string())
+        } else if err.is_connect() {
+            Self::UpstreamUnreachable(format!("could not connect to Ollama: {}", err))
+        } else {
+            Self::UpstreamUnreachable(format!("request to Ollama failed: {}", err))
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::BadRequest(message.into())
+    }
+
+    /// One of axum's own rejections, keeping its status and message
+    fn rejected(rejection: impl IntoResponse + std::fmt::Display) -> Self {
+        let message = rejection.to_string();
+        Self::Other { status: rejection.into_response().status(), message }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Self::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            Self::UpstreamUnreachable(_) | Self::ConnectionLost(_) | Self::Parse(_) => StatusCode::BAD_GATEWAY,
+            Self::UpstreamStatus { status, .. } => *status,
+            Self::Timeout(_) | Self::Stalled(_) => StatusCode::GATEWAY_TIMEOUT,
+            // nginx's "client closed request"
+            Self::ClientGone => StatusCode::from_u16(499).expect("499 is a valid status code"),
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Other { status, .. } => *status,
+        }
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("AppError always serializes")
+    }
+
+    /// `Retry-After` for a 429 or 503, so clients back off instead of hammering a busy backend,
+    /// and the `WWW-Authenticate` challenge that goes with a 401
+    fn headers(&self) -> Option<(header::HeaderName, String)> {
+        match self {
+            Self::Overloaded => Some((header::RETRY_AFTER, OVERLOADED_RETRY_AFTER_SECS.to_string())),
+            Self::RateLimited { retry_after, .. } => Some((header::RETRY_AFTER, retry_after.to_string())),
+            Self::Unauthorized(_) => Some((header::WWW_AUTHENTICATE, "Bearer".to_string())),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadRequest(message)
+            | Self::Unauthorized(message)
+            | Self::Forbidden(message)
+            | Self::NotFound(message)
+            | Self::UpstreamUnreachable(message)
+            | Self::Internal(message)
+            | Self::Other { message, .. } => f.write_str(message),
+            Self::MethodNotAllowed => f.write_str("method not allowed on this route"),
+            Self::RateLimited { scope, .. } => write!(f, "rate limit exceeded for this {}, slow down", scope),
+            Self::PayloadTooLarge { chars, limit } => write!(f, "prompt is {} characters, the limit is {}", chars, limit),
+            Self::Overloaded => f.write_str("too many generations in progress, try again shortly"),
+            Self::UpstreamStatus { status, detail } => match (*status, detail) {
+                (StatusCode::NOT_FOUND, Some(detail)) => write!(f, "model not found: {}", detail),
+                (StatusCode::NOT_FOUND, None) => f.write_str("model not found"),
+                (status, Some(detail)) if status.is_server_error() => write!(f, "Ollama server error ({}): {}", status, detail),
+                (status, None) if status.is_server_error() => write!(f, "Ollama server error ({})", status),
+                (status, Some(detail)) => write!(f, "Ollama responded with {}: {}", status, detail),
+                (status, None) => write!(f, "Ollama responded with {}", status),
+            },
+            Self::Timeout(after) => write!(f, "timed out waiting for Ollama after {}s", after.as_secs()),
+            Self::Stalled(idle) => write!(f, "the model stopped producing tokens for {}s", idle.as_secs()),
+            Self::ConnectionLost(message) => write!(f, "connection to model lost: {}", message),
+            Self::Parse(message) => write!(f, "invalid response from Ollama: {}", message),
+            Self::ClientGone => f.write_str("client disconnected"),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+/// The `{"error": {"code", "message"}}` envelope, with `code` the HTTP status
+impl Serialize for AppError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Envelope<'a> {
+            error: Body<'a>,
+        }
+        #[derive(Serialize)]
+        struct Body<'a> {
+            code: u16,
+            message: &'a str,
+        }
+        let message = self.to_string();
+        Envelope { error: Body { code: self.status().as_u16(), message: &message } }.serialize(serializer)
+    }
+}
+
+/// Validation messages are client errors
+impl From<String> for AppError {
+    fn from(message: String) -> Self {
+        Self::BadRequest(message)
+    }
+}
+
+/// Non-streaming responses report errors as the same JSON, with `code` as the HTTP status
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        (self.status(), AppendHeaders(self.headers()), Json(self)).into_response()
+    }
+}
+
+/// Shared resources handed to every handler
+#[derive(Clone)]
+struct AppState {
+    config: Arc<Config>,
+    /// One pooled client so connections to Ollama are reused across requests
+    client: Client,
+    /// Where chat requests are generated unless routed elsewhere: Ollama
+    backend: Arc<dyn ChatBackend>,
+    /// The hosted `openai` provider, when configured
+    openai: Option<Arc<dyn ChatBackend>>,
+    /// The Ollama hosts behind `backend`, also used for models, health and embeddings
+    upstreams: Arc<Upstreams>,
+    sessions: Arc<SessionStore>,
+    /// Per-IP budget for the chat routes
+    ip_limiter: Arc<RateLimiter>,
+    /// Per session, or per API key (`--session-rate-limit`)
+    session_limiter: Arc<RateLimiter<ClientKey>>,
+    /// Number of spawned `chat_stream` tasks still relaying tokens
+    active_streams: Arc<AtomicUsize>,
+    /// HTTP requests answered since startup, for `/stats`
+    requests_served: Arc<AtomicU64>,
+    started_at: Instant,
+    /// Caps simultaneous generations (`--max-generations`); `None` when unlimited
+    generations: Option<Arc<Semaphore>>,
+    /// Runs the generations (`--workers`); `None` when each stream spawns its own task
+    workers: Option<WorkerPool>,
+    /// Abort handles of running generations, for `POST /abort/:request_id`
+    in_flight: Arc<InFlight>,
+    /// Run on every prompt and every token (`hooks::ChatHook`)
+    hooks: Arc<Hooks>,
+    /// Prometheus counters served at `/metrics`
+    metrics: Arc<Metrics>,
+    /// Used when a request doesn't bring its own `system` prompt
+    default_system_prompt: Option<Arc<str>>,
+    /// Applied to every streamed token
+    cleaning_rules: Arc<ModelRules>,
+    /// Replies to deterministic requests (`--response-cache-size`); `None` when disabled
+    response_cache: Option<Arc<ResponseCache>>,
+    /// Origins the CORS layer allows, replaceable through `PUT /admin/cors`
+    cors_origins: Arc<CorsOrigins>,
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = StreamEvent> + Send>>;
+
 #[tokio::main]
 async fn main() {
-    let cors = CorsLayer::new()
-        .allow_origin(Any)  // ✅ Allows any frontend to connect
-        .allow_methods(vec![Method::GET, Method::POST])  // ✅ Use `http::Method` here
-        .allow_headers([header::CONTENT_TYPE]);  // ✅ Allow Content-Type headers
+    let config = Config::load();
+
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| "chatbot_api=info,tower_http=info,audit=info".into());
+    match config.log_format {
+        LogFormat::Pretty => tracing_subscriber::fmt().with_env_filter(filter).init(),
+        // Each line carries its spans' fields too (method, uri, request_id), ready for a log pipeline
+        LogFormat::Json => tracing_subscriber::fmt().json().with_env_filter(filter).init(),
+    }
+
+    let state = AppState::new(config).await;
+    let app = app(state.clone());
+
+    let addr = state.config.bind;
+    let scheme = if state.config.tls.is_some() { "https" } else { "http" };
+    info!("🚀 Chatbot running at {}://{} (default model {})", scheme, addr, state.config.model);
+    if let Some(file) = &state.config.file {
+        info!("⚙️ Settings loaded from {}", file.display());
+    }
+    if state.config.mock_backend {
+        warn!("🎭 Mock backend: every reply is canned, no model is called. Never use this in production");
+    }
+    info!(
+        "🔗 Using Ollama at {} (connect timeout {}s, request timeout {}s)",
+        state.upstreams.urls().collect::<Vec<_>>().join(", "),
+        state.config.connect_timeout.as_secs(),
+        state.config.request_timeout.as_secs()
+    );
+    if state.config.ollama_urls.len() > 1 {
+        info!(
+            "⚖️ Balancing over {} hosts ({}, {}s cooldown after a failure)",
+            state.config.ollama_urls.len(),
+            state.upstreams.strategy().to_possible_value().expect("no skipped variants").get_name(),
+            state.config.upstream_cooldown.as_secs()
+        );
+    }
+    if let Some(openai) = &state.config.openai {
+        let mut routed: Vec<_> = state
+            .config
+            .model_providers
+            .iter()
+            .filter(|(_, provider)| **provider == Provider::Openai)
+            .map(|(model, _)| model.as_str())
+            .collect();
+        routed.sort_unstable();
+        if routed.is_empty() {
+            info!("☁️ OpenAI provider at {}, for requests with provider=openai", openai.url);
+        } else {
+            info!("☁️ OpenAI provider at {}, serving {}", openai.url, routed.join(", "));
+        }
+    }
+    let seconds = |duration: Option<Duration>| duration.map_or("off".to_string(), |d| format!("{}s", d.as_secs()));
+    info!(
+        "🔌 Upstream pool: {} idle connection(s) per host, idle timeout {}, TCP keepalive {}",
+        state.config.pool_max_idle_per_host,
+        seconds(state.config.pool_idle_timeout),
+        seconds(state.config.tcp_keepalive)
+    );
+    if state.ip_limiter.is_enabled() {
+        info!(
+            "🚦 Rate limit: {} chat request(s) per {}s per IP",
+            state.config.rate_limit,
+            state.config.rate_limit_window.as_secs()
+        );
+    }
+    if state.session_limiter.is_enabled() {
+        info!(
+            "🚦 Rate limit: {} chat request(s) per {}s per API key and per session",
+            state.config.session_rate_limit,
+            state.config.rate_limit_window.as_secs()
+        );
+    }
+    if state.generations.is_some() {
+        info!(
+            "🎛️ At most {} concurrent generation(s), queueing up to {}ms",
+            state.config.max_generations,
+            state.config.queue_timeout.as_millis()
+        );
+    }
+    if state.workers.is_some() {
+        info!(
+            "👷 {} generation worker(s), queueing up to {} request(s)",
+            state.config.workers,
+            state.config.queue_depth
+        );
+    }
+    if state.config.api_keys.is_empty() {
+        warn!("🔓 No API_KEYS configured, chat routes are open to anyone");
+    } else {
+        info!("🔐 API key auth enabled ({} key(s))", state.config.api_keys.len());
+    }
+    if !state.config.allowed_models.is_empty() {
+        info!("🧾 Only these models may be requested: {}", state.config.allowed_models.join(", "));
+    }
+    match state.config.max_sessions {
+        Some(capacity) => info!("🗂️ Sessions expire after {}s idle, at most {} kept", state.sessions.ttl().as_secs(), capacity),
+        None => info!("🗂️ Sessions expire after {}s idle", state.sessions.ttl().as_secs()),
+    }
+    if let Some(path) = &state.config.session_db {
+        info!("💾 Session history persisted to {}", path.display());
+    }
+    if let Some(capacity) = state.config.response_cache_size {
+        info!(
+            "📦 Caching up to {} deterministic replies for {}s",
+            capacity,
+            state.config.response_cache_ttl.as_secs()
+        );
+    }
+    info!("🧽 Cleaning rules: {}", state.cleaning_rules.shared().names().collect::<Vec<_>>().join(", "));
+    let hooks: Vec<_> = state.hooks.names().collect();
+    if !hooks.is_empty() {
+        info!("🪝 Hooks: {}", hooks.join(", "));
+    }
+    let mut own_rules: Vec<_> = state.cleaning_rules.models().collect();
+    if !own_rules.is_empty() {
+        own_rules.sort_unstable();
+        info!("🧽 Model-specific cleaning rules for {}", own_rules.join(", "));
+    }
+    if state.default_system_prompt.is_some() {
+        info!("📝 Default system prompt loaded");
+    }
+    if !state.config.templates.is_empty() {
+        let mut names: Vec<_> = state.config.templates.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        info!("🧩 Prompt templates: {}", names.join(", "));
+    }
+
+    // Both listeners speak HTTP/1.1 and HTTP/2 on the same port, so a browser can multiplex every
+    // tab's `/chat` stream over one connection instead of queueing behind its six-per-host limit.
+    // Browsers only use HTTP/2 over TLS (negotiated through ALPN); on plain HTTP it takes a client
+    // that starts with it, e.g. `curl --http2-prior-knowledge`, or a proxy doing h2c upstream.
+    // WebSockets still open over HTTP/1.1.
+    let active_streams = state.active_streams.clone();
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    match &state.config.tls {
+        Some(tls) => {
+            let rustls = RustlsConfig::from_pem_file(&tls.cert, &tls.key).await.unwrap_or_else(|err| {
+                panic!("failed to load TLS certificate {} / key {}: {}", tls.cert.display(), tls.key.display(), err)
+            });
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                let active_streams = active_streams.clone();
+                async move {
+                    shutdown_signal(active_streams).await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            axum_server::bind_rustls(addr, rustls).handle(handle).serve(service).await.unwrap();
+        }
+        None => {
+            let listener = TcpListener::bind(addr).await.unwrap();
+            axum::serve(listener, service)
+                .with_graceful_shutdown(shutdown_signal(active_streams.clone()))
+                .await
+                .unwrap();
+        }
+    }
+    info!("👋 Shut down cleanly ({} stream(s) still active)", active_streams.load(Ordering::SeqCst));
+}
 
-    let app = Router::new()
+/// All routes and middleware, sharing `state`
+fn app(state: AppState) -> Router {
+    // Every chat route spawns a generation (or an embedding), so they share the rate limiter
+    let chat_routes = Router::new()
+        .route("/chat", get(chat_handler).post(chat_post_handler))
+        .route("/chat/sse", get(chat_sse_handler))
+        .route("/ws", get(ws::ws_handler))
+        .route("/v1/chat/completions", post(openai::completions_handler))
+        .route("/warmup", post(warmup_handler))
+        .route("/generate", post(generate_handler))
+        .route("/embeddings", post(embeddings::embeddings_handler))
+        .route("/title", post(title::title_handler))
+        .route("/regenerate", post(regenerate::regenerate_handler))
+        .route("/abort/:request_id", post(abort::abort_handler))
+        .route_layer(middleware::from_fn(abort::scope_request_id))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_api_key))
+        // Outermost, so guessing keys counts against the rate limit too
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit::limit_requests));
+
+    let admin_routes = Router::new()
+        .route("/admin/cors", get(cors::get_cors_handler).put(cors::put_cors_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_admin_key))
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit::limit_requests));
+
+    Router::new()
         .route("/", get(index_handler))  
-        .route("/chat", get(chat_handler))
-        .layer(cors); // ✅ CORS now correctly attached
+        .merge(chat_routes)
+        .merge(admin_routes)
+        .route("/health", get(health_handler))
+        .route("/models", get(models_handler))
+        .route("/metrics", get(metrics::metrics_handler))
+        .route("/stats", get(stats_handler))
+        .nest_service(
+            "/static",
+            ServeDir::new(&state.config.static_dir).not_found_service(static_not_found.into_service()),
+        )
+        .fallback(route_not_found)
+        .method_not_allowed_fallback(method_not_allowed)
+        // Inside CORS and tracing, so the 500 still carries CORS headers and is logged with the request
+        .layer(CatchPanicLayer::custom(handler_panicked))
+        .layer(cors::cors_layer(&state)) // ✅ CORS now correctly attached
+        // gzip/brotli when the client accepts it; the encoder flushes after every chunk, so streams stay live
+        .layer(CompressionLayer::new())
+        .layer(
+            // One span per request; the response event carries status and latency
+            TraceLayer::new_for_http()
+                .make_span_with(request_span)
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        )
+        .layer(middleware::from_fn(response_time))
+        .layer(middleware::from_fn_with_state(state.clone(), count_request))
+        // Outermost: keep a caller's `X-Request-Id` or mint one, and echo it on the response
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .with_state(state)
+}
+
+/// A handler panicked: log it in the request's span and answer with the usual 500 body,
+/// rather than the connection just closing
+fn handler_panicked(panic: Box<dyn std::any::Any + Send + 'static>) -> Response {
+    error!(panic = panic_message(&*panic), "Handler panicked");
+    AppError::Internal("the request failed unexpectedly".to_string()).into_response()
+}
+
+/// The text a panic was raised with, when it was raised with text
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string panic payload")
+}
+
+/// Resolves on Ctrl-C or SIGTERM; axum then stops accepting and waits for in-flight responses
+async fn shutdown_signal(active_streams: Arc<AtomicUsize>) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to install Ctrl-C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("🛑 Shutdown requested, draining {} active stream(s)", active_streams.load(Ordering::SeqCst));
+}
+
+/// Counts a spawned stream task as active for as long as it is alive
+struct ActiveStreamGuard {
+    counter: Arc<AtomicUsize>,
+    metrics: Arc<Metrics>,
+    /// When the chat request was sent, so the recorded duration covers the whole exchange
+    started: Instant,
+}
+
+impl ActiveStreamGuard {
+    fn new(state: &AppState, started: Instant) -> Self {
+        state.active_streams.fetch_add(1, Ordering::SeqCst);
+        state.metrics.stream_started();
+        Self { counter: state.active_streams.clone(), metrics: state.metrics.clone(), started }
+    }
+}
+
+impl Drop for ActiveStreamGuard {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+        self.metrics.stream_finished(self.started.elapsed());
+    }
+}
+
+impl AppState {
+    /// Build the shared resources and start their background maintenance tasks
+    async fn new(config: Config) -> Self {
+        let mut sessions = SessionStore::new(config.session_ttl).with_capacity(config.max_sessions);
+        if let Some(path) = &config.session_db {
+            // Like a broken rules file, an unusable database aborts startup
+            let db = ConversationDb::open(path)
+                .unwrap_or_else(|err| panic!("failed to open session database {}: {}", path.display(), err));
+            sessions = sessions.with_db(db);
+        }
+        let sessions = Arc::new(sessions);
+        sessions.spawn_sweeper();
+        let default_system_prompt = load_default_system_prompt(&config).await;
+        let client = Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .pool_idle_timeout(config.pool_idle_timeout)
+            .tcp_keepalive(config.tcp_keepalive)
+            .build()
+            .expect("failed to build HTTP client");
+        let ip_limiter = Arc::new(RateLimiter::new(config.rate_limit, config.rate_limit_window));
+        ip_limiter.spawn_pruner();
+        let session_limiter = Arc::new(RateLimiter::new(config.session_rate_limit, config.rate_limit_window));
+        session_limiter.spawn_pruner();
+        let mut cleaning_rules = load_cleaning_rules(&config).await;
+        if config.forward_tool_calls {
+            cleaning_rules.keep_tool_calls();
+        }
+        let cleaning_rules = Arc::new(cleaning_rules);
+        let generations = (config.max_generations > 0).then(|| Arc::new(Semaphore::new(config.max_generations)));
+        let mut hooks = Hooks::default();
+        if config.audit_log {
+            hooks = hooks.with(AuditLog);
+        }
+        if let Some(moderation) = load_moderation(&config).await {
+            hooks = hooks.with(moderation);
+        }
+        let workers = (config.workers > 0).then(|| WorkerPool::new(config.workers, config.queue_depth));
+        let response_cache = config
+            .response_cache_size
+            .map(|capacity| Arc::new(ResponseCache::new(capacity, config.response_cache_ttl)));
+        let config = Arc::new(config);
+        let upstreams = Arc::new(Upstreams::new(&config));
+        let cors_origins = Arc::new(CorsOrigins::new(&config));
+        let (backend, openai): (Arc<dyn ChatBackend>, _) = if config.mock_backend {
+            let mock: Arc<dyn ChatBackend> = Arc::new(MockBackend);
+            (mock.clone(), Some(mock))
+        } else {
+            let openai = config.openai.clone().map(|upstream| {
+                Arc::new(OpenAiBackend::new(client.clone(), config.clone(), upstream)) as Arc<dyn ChatBackend>
+            });
+            (Arc::new(OllamaBackend::new(client.clone(), config.clone(), upstreams.clone())), openai)
+        };
+
+        Self {
+            config,
+            ip_limiter,
+            session_limiter,
+            client,
+            backend,
+            openai,
+            upstreams,
+            sessions,
+            active_streams: Arc::new(AtomicUsize::new(0)),
+            requests_served: Arc::new(AtomicU64::new(0)),
+            started_at: Instant::now(),
+            generations,
+            workers,
+            in_flight: Arc::new(InFlight::default()),
+            hooks: Arc::new(hooks),
+            metrics: Arc::new(Metrics::new()),
+            default_system_prompt,
+            cleaning_rules,
+            response_cache,
+            cors_origins,
+        }
+    }
+}
+
+/// Held by a `chat_stream` task for as long as it runs; `None` when generations are unlimited
+type GenerationPermit = Option<OwnedSemaphorePermit>;
+
+impl AppState {
+    /// The backend generating for `model`: the request's `provider`, else its `--model-providers` route, else Ollama
+    fn backend_for(&self, model: &str, provider: Option<Provider>) -> Result<Arc<dyn ChatBackend>, AppError> {
+        match self.config.provider_for(model, provider) {
+            Provider::Ollama => Ok(self.backend.clone()),
+            Provider::Openai => {
+                self.openai.clone().ok_or_else(|| AppError::bad_request("the openai provider is not configured"))
+            }
+        }
+    }
+
+    /// A free generation slot, waiting up to `--queue-timeout-ms` for one; 503 once that runs out
+    async fn generation_permit(&self) -> Result<GenerationPermit, AppError> {
+        let Some(generations) = &self.generations else {
+            return Ok(None);
+        };
+        match tokio::time::timeout(self.config.queue_timeout, generations.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(Some(permit)),
+            _ => {
+                warn!(limit = self.config.max_generations, "All generation slots busy, rejecting request");
+                Err(AppError::Overloaded)
+            }
+        }
+    }
+}
+
+/// Token cleaning rules from `--cleaning-rules`, or the built-in set.
+/// A broken rules file aborts startup rather than silently changing output.
+async fn load_cleaning_rules(config: &Config) -> ModelRules {
+    let Some(path) = &config.cleaning_rules else {
+        return ModelRules::default();
+    };
+    let json = fs::read_to_string(path)
+        .await
+        .unwrap_or_else(|err| panic!("failed to read cleaning rules {}: {}", path.display(), err));
+    ModelRules::from_json(&json).unwrap_or_else(|err| panic!("invalid cleaning rules {}: {}", path.display(), err))
+}
+
+/// The `--moderation-file` blocklist; a file that can't be read or parsed stops startup, since
+/// serving without the moderation the operator asked for is worse than not serving
+async fn load_moderation(config: &Config) -> Option<Moderation> {
+    let path = config.moderation_file.as_ref()?;
+    let list = fs::read_to_string(path)
+        .await
+        .unwrap_or_else(|err| panic!("failed to read moderation file {}: {}", path.display(), err));
+    let moderation = Moderation::parse(&list, config.moderation_redact)
+        .unwrap_or_else(|err| panic!("invalid moderation file {}: {}", path.display(), err));
+    Some(moderation)
+}
+
+/// Default system prompt from `--system-prompt`, or from the file named by `--system-prompt-file`
+async fn load_default_system_prompt(config: &Config) -> Option<Arc<str>> {
+    if let Some(prompt) = &config.system_prompt {
+        return Some(prompt.as_str().into());
+    }
+    let path = config.system_prompt_file.as_ref()?;
+    match fs::read_to_string(path).await {
+        Ok(prompt) => Some(prompt.trim().into()),
+        Err(err) => {
+            warn!(path = %path.display(), error = ?err, "Failed to read system prompt file");
+            None
+        }
+    }
+}
+
+/// Copy of the frontend baked in at build time, so the binary works from any directory
+const EMBEDDED_INDEX: &str = include_str!("../index.html");
+
+/// Span wrapping everything logged for one request, `chat_stream`'s relay task included
+fn request_span(request: &axum::extract::Request) -> tracing::Span {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        request_id = %request_id,
+    )
+}
 
-    let addr: SocketAddr = "0.0.0.0:8000".parse().unwrap();
-    println!("🚀 Chatbot running at http://{}", addr);
+/// Set `X-Response-Time` to the milliseconds until the response started. For a stream that's the
+/// time to its headers (on `/chat`, sent with the first token), not to the end of the answer.
+async fn response_time(request: axum::extract::Request, next: middleware::Next) -> Response {
+    let started = Instant::now();
+    let mut response = next.run(request).await;
+    let elapsed = format!("{:.3}ms", started.elapsed().as_secs_f64() * 1000.0);
+    if let Ok(value) = header::HeaderValue::from_str(&elapsed) {
+        response.headers_mut().insert(RESPONSE_TIME_HEADER, value);
+    }
+    response
+}
 
-    let listener = TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+/// Count every request for `/stats`
+async fn count_request(
+    State(state): State<AppState>,
+    request: axum::extract::Request,
+    next: middleware::Next,
+) -> Response {
+    state.requests_served.fetch_add(1, Ordering::Relaxed);
+    next.run(request).await
 }
 
-/// Serve the index.html file
-async fn index_handler() -> impl IntoResponse {
-    match fs::read_to_string("index.html").await {
+/// Serve `--index-file`: the on-disk copy when there is one (handy for live editing), else the embedded one
+async fn index_handler(State(state): State<AppState>) -> impl IntoResponse {
+    match fs::read_to_string(&state.config.index_file).await {
         Ok(content) => Html(content).into_response(),
-        Err(_) => Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
-            .body("index.html not found".into())
-            .unwrap(),
+        Err(_) => Html(EMBEDDED_INDEX).into_response(),
     }
 }
 
-/// Chat handler
-async fn chat_handler(Query(params): Query<HashMap<String, String>>) -> impl IntoResponse {
-    println!("Received request with params: {:?}", params);
-    let prompt = params.get("prompt").cloned().unwrap_or_else(|| "Hello".to_string());
+/// Fallback for `/static` paths that don't match a file
+async fn static_not_found() -> AppError {
+    AppError::NotFound("file not found".to_string())
+}
 
-    println!("🔹 Sending to Ollama: {}", prompt);
+/// Fallback for paths no route matches
+async fn route_not_found(uri: axum::http::Uri) -> AppError {
+    AppError::NotFound(format!("no route for {}", uri.path()))
+}
 
-    let stream = chat_stream(prompt).await;
+async fn method_not_allowed() -> AppError {
+    AppError::MethodNotAllowed
+}
 
-    Response::builder()
-        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
-        .body(axum::body::Body::from_stream(stream))
-        .unwrap()
+/// Readiness probe: 200 when Ollama answers `/api/tags`, 503 otherwise
+async fn health_handler(State(state): State<AppState>) -> Response {
+    let upstream = state.upstreams.pick().endpoint("/api/tags");
+    match check_upstream(&state, &upstream).await {
+        Ok(_) => Json(serde_json::json!({ "status": "ok", "upstream": upstream })).into_response(),
+        Err(err) => {
+            let error = AppError::Other { status: StatusCode::SERVICE_UNAVAILABLE, message: err.to_string() };
+            // The `error` envelope of any other failure, with the probe's own fields around it
+            let mut body = serde_json::to_value(&error).expect("AppError always serializes");
+            body["status"] = "unavailable".into();
+            body["upstream"] = upstream.into();
+            (error.status(), Json(body)).into_response()
+        }
+    }
 }
 
-/// **Fast cleaning of response content**
-fn clean_content(raw: &str) -> String {
-    let text = CONTROL_REGEX.replace_all(raw, "");
-    let text = UNKNOWN_REGEX.replace_all(&text, "");
-    let text = TOOL_REGEX.replace_all(&text, "");
-    MULTI_SPACE.replace_all(&text, " ").into_owned()
+/// Whether Ollama answers `upstream` (its `/api/tags`) within `HEALTH_CHECK_TIMEOUT`
+async fn check_upstream(state: &AppState, upstream: &str) -> Result<(), reqwest::Error> {
+    state.client.get(upstream).timeout(HEALTH_CHECK_TIMEOUT).send().await?.error_for_status()?;
+    Ok(())
 }
 
-/// **Chat stream with efficient processing**
-async fn chat_stream(prompt: String) -> Pin<Box<dyn Stream<Item = Result<String, std::io::Error>> + Send>> {
-    let client = Client::new();
-    let request = ChatRequest {
-        model: "mistral".to_string(),
-        messages: vec![Message { role: "user".to_string(), content: prompt }],
-        stream: true,
-    };
-
-    let response = match client.post("http://localhost:11434/api/chat")
-        .json(&request)
-        .send()
-        .await 
-    {
-        Ok(resp) => resp,
+/// Snapshot served by `/stats`
+#[derive(Debug, Serialize)]
+struct Stats {
+    uptime_secs: u64,
+    requests_served: u64,
+    active_streams: usize,
+    /// Generations waiting for a worker, when there is a pool
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queued: Option<usize>,
+    model: String,
+    upstreams: Vec<String>,
+    ollama_reachable: bool,
+}
+
+/// `GET /stats`: live server state as plain JSON, for a glance without a metrics stack
+async fn stats_handler(State(state): State<AppState>) -> Json<Stats> {
+    let upstream = state.upstreams.pick().endpoint("/api/tags");
+    Json(Stats {
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        requests_served: state.requests_served.load(Ordering::Relaxed),
+        active_streams: state.active_streams.load(Ordering::SeqCst),
+        queued: state.workers.as_ref().map(WorkerPool::queued),
+        model: state.config.model.clone(),
+        upstreams: state.upstreams.urls().map(str::to_string).collect(),
+        ollama_reachable: check_upstream(&state, &upstream).await.is_ok(),
+    })
+}
+
+/// List the models installed in Ollama, for populating a model picker
+async fn models_handler(State(state): State<AppState>) -> Response {
+    let upstream = state.upstreams.pick().endpoint("/api/tags");
+    let result = async {
+        state.client.get(&upstream).send().await?.error_for_status()?.json::<TagsResponse>().await
+    }
+    .await;
+
+    match result {
+        Ok(tags) => Json(tags.models).into_response(),
         Err(err) => {
-            eprintln!("Error fetching response: {:?}", err);
-            return Box::pin(tokio_stream::once(Err(std::io::Error::new(std::io::ErrorKind::Other, "API request failed"))));
+            warn!(error = ?err, "Error listing models");
+            AppError::UpstreamUnreachable(format!("could not list models from {}: {}", upstream, err)).into_response()
         }
+    }
+}
+
+/// How long `POST /warmup` took to get the model loaded
+#[derive(Debug, Serialize)]
+struct Warmup {
+    model: String,
+    elapsed_ms: u64,
+}
+
+/// `POST /warmup?model=...&keep_alive=...`: load a model (by default the configured one) before
+/// the first user needs it. Without `keep_alive`, Ollama's own default applies.
+async fn warmup_handler(
+    State(state): State<AppState>,
+    QueryParams(params): QueryParams,
+) -> Result<Json<Warmup>, AppError> {
+    let model = params.get("model").cloned().unwrap_or_else(|| state.config.model.clone());
+    validate_model(&state.config, &model)?;
+    let keep_alive: Option<KeepAlive> = parse_param(&params, "keep_alive")?;
+    if let Some(keep_alive) = &keep_alive {
+        keep_alive.validate()?;
+    }
+    let backend = state.backend_for(&model, parse_param(&params, "provider")?)?;
+
+    let started = Instant::now();
+    backend.warm_up(&model, keep_alive).await?;
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    info!(%model, elapsed_ms, "🔥 Model warmed up");
+    Ok(Json(Warmup { model, elapsed_ms }))
+}
+
+/// Chat handler
+async fn chat_handler(
+    State(state): State<AppState>,
+    QueryParams(params): QueryParams,
+) -> impl IntoResponse {
+    debug!(?params, "Received chat request");
+    let raw = match parse_param(&params, "raw") {
+        Ok(raw) => raw.unwrap_or(false),
+        Err(message) => return bad_request(message),
+    };
+    let done_frame = match done_frame(&params) {
+        Ok(done_frame) => done_frame,
+        Err(message) => return bad_request(message),
     };
+    if raw && params.contains_key("session_id") {
+        // The reply is never assembled, so there'd be nothing to record
+        return bad_request("raw mode doesn't support session_id".to_string());
+    }
+    if raw && state.config.moderation_redact {
+        // Raw lines skip the token hooks, so redaction would quietly not happen
+        return bad_request("raw mode is disabled while replies are moderated".to_string());
+    }
+    let (request, session) = match query_request(&state, &params).await {
+        Ok(parsed) => parsed,
+        Err(err) => return err.into_response(),
+    };
+    if raw {
+        raw_chat_response(&state, request).await
+    } else if request.stream {
+        stream_chat_response(&state, request, session, done_frame).await
+    } else {
+        complete_chat_response(&state, request, session).await
+    }
+}
+
+/// Chat handler taking a full conversation as a JSON body
+async fn chat_post_handler(
+    State(state): State<AppState>,
+    QueryParams(params): QueryParams,
+    JsonBody(mut request): JsonBody<ChatRequest>,
+) -> impl IntoResponse {
+    debug!(messages = request.messages.len(), "Received chat request");
+    let done_frame = match done_frame(&params) {
+        Ok(done_frame) => done_frame,
+        Err(message) => return bad_request(message),
+    };
+    if request.model.is_empty() {
+        request.model = state.config.model.clone();
+    }
+    if let Err(err) = check_prompt_length(&state.config, &request.messages) {
+        return err.into_response();
+    }
+    if request.stream {
+        stream_chat_response(&state, request, None, done_frame).await
+    } else {
+        complete_chat_response(&state, request, None).await
+    }
+}
 
-    let (tx, rx) = mpsc::channel(20);
+/// `POST /generate` with `{"model", "prompt", "stream"}`: a plain completion, streamed as text
+/// or, with `stream: false`, returned as one `ChatCompletion`
+async fn generate_handler(
+    State(state): State<AppState>,
+    QueryParams(params): QueryParams,
+    JsonBody(mut request): JsonBody<GenerateRequest>,
+) -> Response {
+    let done_frame = match done_frame(&params) {
+        Ok(done_frame) => done_frame,
+        Err(message) => return bad_request(message),
+    };
+    if request.model.is_empty() {
+        request.model = state.config.model.clone();
+    }
+    if let Err(err) = validate_generate(&state.config, &request) {
+        return err.into_response();
+    }
+    let permit = match state.generation_permit().await {
+        Ok(permit) => permit,
+        Err(err) => return err.into_response(),
+    };
 
-    tokio::spawn(async move {
-        let mut stream = response.bytes_stream();
-        while let Some(chunk) = stream.next().await {
-            if let Ok(bytes) = chunk {
-                let text = String::from_utf8_lossy(&bytes);
-                if let Ok(parsed) = serde_json::from_str::<ChatStreamResponse>(&text) {
-                    if let Some(msg) = parsed.message {
-                        let cleaned = clean_content(&msg.content);
-                        if !cleaned.is_empty() {
-                            let _ = tx.send(Ok(cleaned + " ")).await;
-                        }
-                    }
-                }
-            }
-        }
-    });
+    let model = request.model.clone();
+    let stream = request.stream;
+    let events = generate_stream(&state, request, permit).await;
+    if stream {
+        text_response(events, done_frame).await
+    } else {
+        completion_response(model, events).await
+    }
+}
 
-    Box::pin(ReceiverStream::new(rx))
-}
\ No newline at end of file
+/// Same as `chat_handler`, but framed as Server-Sent Events for `EventSource` clients.
+/// Each cleaned token is its own unnamed event, in order and exactly as the model produced it,
+/// leading spaces and line breaks included, so clients append `event.data` as is.
+async fn chat_sse_handler(
+    State(state): State<AppState>,
+    QueryParams(params): QueryParams,
+) -> Response {
+    debug!(?params, "Received SSE chat request");
+    let (mut request, session) = match query_request(&state, &params).await {
+        Ok(parsed) => parsed,
+        Err(err) => return err.into_response(),
+    };
+    let permit = match state.generation_permit().await {
+        Ok(permit) => permit,
+        Err(err) => return err.into_response(),
+    };
+    apply_system_prompt(&state, &mut request);
+
+    let events = chat_stream(&state, request, session, permit).await.flat_map(|item| {
+        let frames = match item {
+            StreamEvent::Token(token) => vec![Event::default().data(sse_data(&token))],
+            // Usage goes out as its own frame so `done` stays a plain terminator
+            StreamEvent::Done(usage) => vec![
+                Event::default().event("usage").json_data(&usage).expect("Usage always serializes"),
+                Event::default().event("done").data("[DONE]"),
+            ],
+            StreamEvent::ToolCalls(calls) => vec![Event::default().event("tool_calls").data(calls.to_string())],
+            StreamEvent::Error(err) => vec![Event::default().event("error").data(err.to_json())],
+        };
+        futures::stream::iter(frames.into_iter().map(Ok::<_, std::io::Error>))
+    });
+
+    sse_response(&state.config, events)
+}
+
+/// An SSE response that, with `--sse-keep-alive-secs`, sends a `: keep-alive` comment whenever
+/// no event has gone out for that long, so proxies don't close the connection on a slow model
+fn sse_response<S>(config: &Config, events: S) -> Response
+where
+    S: Stream<Item = Result<Event, std::io::Error>> + Send + 'static,
+{
+    match config.sse_keep_alive {
+        Some(interval) => {
+            let keep_alive = SseKeepAlive::new().interval(interval).text("keep-alive");
+            Sse::new(events).keep_alive(keep_alive).into_response()
+        }
+        None => Sse::new(events).into_response(),
+    }
+}
+
+/// Build a request from the `prompt` and `model` query parameters.
+/// With a `session_id`, the whole history is sent with the prompt at the end; the session keeps the
+/// prompt once the reply to it completes.
+async fn query_request(
+    state: &AppState,
+    params: &HashMap<String, String>,
+) -> Result<(ChatRequest, Option<SessionHandle>), AppError> {
+    let prompt = match (params.get("template"), params.get("prompt"), &state.config.default_prompt) {
+        // The other parameters fill the template's placeholders
+        (Some(name), _, _) => {
+            let template = state
+                .config
+                .templates
+                .get(name)
+                .ok_or_else(|| AppError::bad_request(format!("unknown template: {:?}", name)))?;
+            template::render(name, template, params)?
+        }
+        (None, Some(prompt), _) => prompt.clone(),
+        (None, None, Some(default)) => default.clone(),
+        (None, None, None) => return Err(AppError::bad_request("prompt is required")),
+    };
+    if prompt.trim().is_empty() {
+        return Err(AppError::bad_request("prompt must not be empty"));
+    }
+    let model = params.get("model").cloned().unwrap_or_else(|| state.config.model.clone());
+    let user_message = Message { role: "user".to_string(), content: prompt, images: Vec::new() };
+    let system = params.get("system").cloned();
+    let options = ChatOptions::from_params(params)?;
+
+    let stream = parse_param(params, "stream")?.unwrap_or(true);
+    let keep_alive = parse_param(params, "keep_alive")?;
+    let provider = parse_param(params, "provider")?;
+
+    let mut request = ChatRequest {
+        model,
+        messages: vec![user_message],
+        stream,
+        system,
+        options,
+        keep_alive,
+        format: None,
+        provider,
+    };
+    // Reject bad input before it can touch a session's history. Only the new prompt is
+    // measured, so a long-running session doesn't start failing once its history grows.
+    validate_request(&state.config, &request)?;
+    check_prompt_length(&state.config, &request.messages)?;
+
+    let session = match params.get("session_id") {
+        Some(id) if id.is_empty() || id.len() > MAX_SESSION_ID_LEN => {
+            return Err(format!("session_id must be 1-{} characters", MAX_SESSION_ID_LEN).into());
+        }
+        Some(id) => {
+            // Only recorded along with its reply, once there is one
+            let prompt = request.messages.pop().unwrap();
+            request.messages = state.sessions.history(id).await;
+            request.messages.push(prompt.clone());
+            Some(SessionHandle { store: state.sessions.clone(), id: id.clone(), prompt })
+        }
+        None => None,
+    };
+
+    Ok((request, session))
+}
+
+/// Parse an optional query parameter, rejecting values that don't parse as `T`
+fn parse_param<T: std::str::FromStr>(params: &HashMap<String, String>, key: &str) -> Result<Option<T>, String> {
+    params
+        .get(key)
+        .map(|raw| raw.parse().map_err(|_| format!("invalid {}: {:?}", key, raw)))
+        .transpose()
+}
+
+fn validate_request(config: &Config, request: &ChatRequest) -> Result<(), String> {
+    validate_model(config, &request.model)?;
+    validate_provider(config, &request.model, request.provider)?;
+    if request.messages.is_empty() {
+        return Err("messages must not be empty".to_string());
+    }
+    for (index, message) in request.messages.iter().enumerate() {
+        if !MESSAGE_ROLES.contains(&message.role.as_str()) {
+            return Err(format!(
+                "messages[{}].role must be one of {}, got {:?}",
+                index,
+                MESSAGE_ROLES.join(", "),
+                message.role
+            ));
+        }
+        if message.images.iter().any(|image| BASE64.decode(image).is_err()) {
+            return Err(format!("messages[{}].images must be base64-encoded", index));
+        }
+    }
+    if let Some(options) = &request.options {
+        options.validate()?;
+    }
+    if let Some(keep_alive) = &request.keep_alive {
+        keep_alive.validate()?;
+    }
+    if let Some(format) = &request.format {
+        format.validate()?;
+    }
+    Ok(())
+}
+
+fn validate_generate(config: &Config, request: &GenerateRequest) -> Result<(), AppError> {
+    validate_model(config, &request.model)?;
+    validate_provider(config, &request.model, request.provider)?;
+    if request.prompt.trim().is_empty() {
+        return Err(AppError::bad_request("prompt must not be empty"));
+    }
+    if let Some(options) = &request.options {
+        options.validate()?;
+    }
+    if let Some(keep_alive) = &request.keep_alive {
+        keep_alive.validate()?;
+    }
+    check_prompt_chars(config, request.prompt.chars().count())
+}
+
+/// 413 when `messages` hold more than `--max-prompt-chars` characters in total
+fn check_prompt_length(config: &Config, messages: &[Message]) -> Result<(), AppError> {
+    check_prompt_chars(config, messages.iter().map(|message| message.content.chars().count()).sum())
+}
+
+fn check_prompt_chars(config: &Config, chars: usize) -> Result<(), AppError> {
+    if config.max_prompt_chars == 0 {
+        return Ok(());
+    }
+    if chars > config.max_prompt_chars {
+        return Err(AppError::PayloadTooLarge { chars, limit: config.max_prompt_chars });
+    }
+    Ok(())
+}
+
+/// Reject malformed model names, and with `--allowed-models` any model not on the list
+fn validate_model(config: &Config, model: &str) -> Result<(), String> {
+    if !MODEL_NAME_REGEX.is_match(model) {
+        return Err(format!("invalid model name: {:?}", model));
+    }
+    if !config.allows_model(model) {
+        return Err(format!("model {:?} is not allowed", model));
+    }
+    Ok(())
+}
+
+/// The provider `model` would be sent to must be configured
+fn validate_provider(config: &Config, model: &str, requested: Option<Provider>) -> Result<(), String> {
+    match config.provider_for(model, requested) {
+        Provider::Openai if config.openai.is_none() => {
+            Err("the openai provider is not configured; set OPENAI_BASE_URL or OPENAI_API_KEY".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Trim the oldest history off `request` until it fits the model's context budget, if it has one.
+/// Only what's sent is trimmed: a session keeps its whole history.
+fn fit_context(config: &Config, request: &mut ChatRequest) {
+    let budget = config.context_budget_for(&request.model);
+    if budget == 0 {
+        return;
+    }
+    let dropped = context::trim_to_budget(&mut request.messages, budget);
+    if dropped > 0 {
+        debug!(dropped, budget, kept = request.messages.len(), "Trimmed history to fit the context budget");
+    }
+}
+
+/// Prepend the request's `system` prompt, or the configured default, as a leading `system` message.
+/// Done at send time so the prompt never ends up in stored session history.
+fn apply_system_prompt(state: &AppState, request: &mut ChatRequest) {
+    let system = request
+        .system
+        .take()
+        .or_else(|| state.default_system_prompt.as_deref().map(str::to_string));
+    if let Some(content) = system {
+        request.messages.insert(0, Message { role: "system".to_string(), content, images: Vec::new() });
+    }
+    merge_system_messages(&mut request.messages);
+}
+
+/// Between system prompts merged into one
+const SYSTEM_PROMPT_SEPARATOR: &str = "\n\n";
+
+/// Fold every `system` message into a single leading one, in their original order, since some
+/// models ignore or stumble over a second system prompt (e.g. the default one plus the client's)
+fn merge_system_messages(messages: &mut Vec<Message>) {
+    if messages.iter().filter(|message| message.role == "system").count() < 2 {
+        return;
+    }
+    let (system, rest): (Vec<_>, Vec<_>) =
+        std::mem::take(messages).into_iter().partition(|message| message.role == "system");
+    let content: Vec<_> = system.iter().map(|message| message.content.as_str()).collect();
+    let content = content.join(SYSTEM_PROMPT_SEPARATOR);
+    let images = system.into_iter().flat_map(|message| message.images).collect();
+    messages.push(Message { role: "system".to_string(), content, images });
+    messages.extend(rest);
+}
+
+/// Shared by the GET and POST chat routes: validate, then stream the cleaned tokens back
+async fn stream_chat_response(
+    state: &AppState,
+    mut request: ChatRequest,
+    session: Option<SessionHandle>,
+    done_frame: bool,
+) -> Response {
+    if let Err(message) = validate_request(&state.config, &request) {
+        return bad_request(message);
+    }
+    let permit = match state.generation_permit().await {
+        Ok(permit) => permit,
+        Err(err) => return err.into_response(),
+    };
+    apply_system_prompt(state, &mut request);
+
+    debug!(prompt = ?request.messages.last().map(|m| &m.content), "🔹 Sending to Ollama");
+
+    let events = chat_stream(state, request, session, permit).await;
+    text_response(events, done_frame).await
+}
+
+/// `done=true`: end a plain-text stream with a `{"done": true, ...}` line, see `text_response`
+fn done_frame(params: &HashMap<String, String>) -> Result<bool, String> {
+    Ok(parse_param(params, "done")?.unwrap_or(false))
+}
+
+/// Tokens as they come, as `text/plain`. With `done_frame`, a complete answer ends with
+/// `{"done": true, "content": ..., "usage": ...}` on a line of its own, the assembled text included,
+/// so clients can tell it from a dropped connection; without it the body is the model's text alone.
+async fn text_response(mut events: EventStream, done_frame: bool) -> Response {
+    // Nothing has been sent yet, so a failure to start (unknown model, Ollama down) gets a real status
+    let first = events.next().await;
+    if let Some(StreamEvent::Error(err)) = first {
+        return err.into_response();
+    }
+
+    let mut content = String::new();
+    let text = futures::stream::iter(first).chain(events).filter_map(move |item| {
+        let frame = match item {
+            // Ollama's tokens already carry their own spacing
+            StreamEvent::Token(token) => {
+                if done_frame {
+                    content.push_str(&token);
+                }
+                Some(token)
+            }
+            StreamEvent::Done(usage) => done_frame.then(|| {
+                let done = serde_json::json!({ "done": true, "content": std::mem::take(&mut content), "usage": usage });
+                format!("\n{}\n", done)
+            }),
+            // On a line of its own, like errors, so clients can pick it out of the text
+            StreamEvent::ToolCalls(calls) => Some(format!("\n{}\n", serde_json::json!({ "tool_calls": calls }))),
+            // Say why the answer stopped instead of just cutting the connection
+            StreamEvent::Error(err) => Some(format!("\n{}\n", err.to_json())),
+        };
+        future::ready(frame.map(Ok::<_, std::io::Error>))
+    });
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(axum::body::Body::from_stream(text))
+        .unwrap()
+}
+
+/// `raw=true`: relay Ollama's NDJSON lines verbatim, skipping cleaning and tool-call parsing
+async fn raw_chat_response(state: &AppState, mut request: ChatRequest) -> Response {
+    let permit = match state.generation_permit().await {
+        Ok(permit) => permit,
+        Err(err) => return err.into_response(),
+    };
+    apply_system_prompt(state, &mut request);
+    request.stream = true;
+    // Only the prompt: the tokens are relayed untouched
+    if let Err(err) = state.hooks.before_request(&mut request.messages) {
+        return err.into_response();
+    }
+    fit_context(&state.config, &mut request);
+
+    state.metrics.chat_request();
+    let started = Instant::now();
+    let backend = match state.backend_for(&request.model, request.provider) {
+        Ok(backend) => backend,
+        Err(err) => return err.into_response(),
+    };
+    let lines = match backend.chat_raw(&request).await {
+        Ok(lines) => lines,
+        Err(err) => {
+            state.metrics.upstream_failure(failure_kind(&err));
+            return err.into_response();
+        }
+    };
+
+    // Both live as long as the body, which the client drops when it goes away
+    let guard = ActiveStreamGuard::new(state, started);
+    let body = lines.map(move |line| {
+        let _ = (&guard, &permit);
+        match line {
+            Ok(line) => Ok::<_, std::io::Error>(format!("{}\n", line)),
+            // A final JSON line of our own, like the plain-text stream's error line
+            Err(err) => Ok(format!("{}\n", err.to_json())),
+        }
+    });
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(axum::body::Body::from_stream(body))
+        .unwrap()
+}
+
+/// Answer of `/chat` with `stream=false`
+#[derive(Debug, Serialize)]
+struct ChatCompletion {
+    model: String,
+    content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<serde_json::Value>,
+}
+
+/// Buffer the whole cleaned answer and return it as one JSON object once Ollama is done
+async fn complete_chat_response(
+    state: &AppState,
+    mut request: ChatRequest,
+    session: Option<SessionHandle>,
+) -> Response {
+    if let Err(message) = validate_request(&state.config, &request) {
+        return bad_request(message);
+    }
+    let permit = match state.generation_permit().await {
+        Ok(permit) => permit,
+        Err(err) => return err.into_response(),
+    };
+    apply_system_prompt(state, &mut request);
+
+    let model = request.model.clone();
+    let events = chat_stream(state, request, session, permit).await;
+    completion_response(model, events).await
+}
+
+/// The whole answer in one `ChatCompletion`, or the error status that cut it short
+async fn completion_response(model: String, events: EventStream) -> Response {
+    match collect_reply(events).await {
+        Ok((content, tool_calls)) => Json(ChatCompletion { model, content, tool_calls }).into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Wait for the whole reply: its text, and the tool calls it made if any
+async fn collect_reply(mut events: EventStream) -> Result<(String, Option<serde_json::Value>), AppError> {
+    let mut content = String::new();
+    let mut tool_calls = None;
+    while let Some(event) = events.next().await {
+        match event {
+            StreamEvent::Token(token) => content.push_str(&token),
+            StreamEvent::ToolCalls(calls) => tool_calls = Some(calls),
+            StreamEvent::Done(_) => break,
+            StreamEvent::Error(err) => return Err(err),
+        }
+    }
+    Ok((content, tool_calls))
+}
+
+/// SSE can't carry bare carriage returns; `Event::data` splits the remaining newlines into `data:` fields
+fn sse_data(token: &str) -> String {
+    token.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// 400 with the same `{"error": {"code", "message"}}` JSON the streams use
+fn bad_request(message: String) -> Response {
+    AppError::bad_request(message).into_response()
+}
+
+/// `Json`, but a body that doesn't parse as `T` gets the same 400 JSON as any other
+/// bad request, naming the offending field, e.g. `messages[0]: missing field "content"`
+struct JsonBody<T>(T);
+
+#[axum::async_trait]
+impl<T, S> FromRequest<S> for JsonBody<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: axum::extract::Request, state: &S) -> Result<Self, Self::Rejection> {
+        let rejection = match Json::<T>::from_request(request, state).await {
+            Ok(Json(value)) => return Ok(Self(value)),
+            Err(rejection) => rejection,
+        };
+        let message = match &rejection {
+            // serde's message already carries the path to the field, e.g. `stream: invalid type: ...`
+            JsonRejection::JsonDataError(err) => format!("invalid request body: {}", serde_error(&err.body_text())),
+            JsonRejection::JsonSyntaxError(err) => format!("malformed JSON: {}", serde_error(&err.body_text())),
+            JsonRejection::MissingJsonContentType(_) => "expected a `Content-Type: application/json` body".to_string(),
+            // Too large, or unreadable: keep axum's own status
+            _ => return Err(AppError::rejected(rejection).into_response()),
+        };
+        Err(bad_request(message))
+    }
+}
+
+/// Query parameters by name. Unlike `Query`, which quietly turns bytes that aren't UTF-8 into
+/// U+FFFD, a value that doesn't decode cleanly is rejected with a 400 naming the parameter.
+struct QueryParams(HashMap<String, String>);
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for QueryParams {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut axum::http::request::Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parse_query(parts.uri.query().unwrap_or_default()).map(Self).map_err(bad_request)
+    }
+}
+
+/// Strictly decode an `application/x-www-form-urlencoded` query string; the last of repeated keys wins
+fn parse_query(query: &str) -> Result<HashMap<String, String>, String> {
+    let mut params = HashMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (raw_key, raw_value) = pair.split_once('=').unwrap_or((pair, ""));
+        let key = percent_decode(raw_key)
+            .map_err(|_| format!("query parameter name {:?} is not valid UTF-8 once decoded", raw_key))?;
+        let value = percent_decode(raw_value)
+            .map_err(|_| format!("query parameter {:?} is not valid UTF-8 once decoded", key))?;
+        params.insert(key, value);
+    }
+    Ok(params)
+}
+
+/// `+` is a space and `%XX` a byte; a `%` not followed by two hex digits is kept as is
+fn percent_decode(raw: &str) -> Result<String, std::string::FromUtf8Error> {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 2;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8(decoded)
+}
+
+/// The serde part of an axum rejection message, without axum's generic preamble
+fn serde_error(body_text: &str) -> &str {
+    body_text.split_once(": ").map_or(body_text, |(_, detail)| detail)
+}
+
+/// Incremental UTF-8 decoder that holds back a multi-byte sequence split across chunks
+#[derive(Debug, Default)]
+struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    /// Decode as much of `pending + bytes` as possible, keeping an incomplete tail for later
+    fn decode(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut out = String::new();
+        let mut rest: &[u8] = &self.pending;
+
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    out.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(err) => {
+                    let (valid, after) = rest.split_at(err.valid_up_to());
+                    // `valid_up_to` guarantees this prefix is valid UTF-8
+                    out.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                    match err.error_len() {
+                        // Genuinely invalid bytes: replace them and keep going
+                        Some(len) => {
+                            out.push(char::REPLACEMENT_CHARACTER);
+                            rest = &after[len..];
+                        }
+                        // Truncated sequence at the end: wait for the next chunk
+                        None => {
+                            rest = after;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        self.pending = rest.to_vec();
+        out
+    }
+
+    /// Flush whatever is left once the stream has ended
+    fn finish(self) -> String {
+        String::from_utf8_lossy(&self.pending).into_owned()
+    }
+}
+
+/// What each token of a stream goes through before reaching the client
+struct TokenFilters {
+    /// The model's cleaning rules
+    rules: Arc<CleaningRules>,
+    /// Then each hook's `on_token`
+    hooks: Arc<Hooks>,
+}
+
+/// Clean one backend chunk into the events sent to clients
+fn clean_chunk(
+    chunk: Chunk,
+    filters: &TokenFilters,
+    state: &mut CleanState,
+    tools: Option<&mut ToolCallParser>,
+) -> Vec<StreamEvent> {
+    let events = match chunk {
+        Chunk::Content(raw) => {
+            let cleaned = clean_content(&raw, &filters.rules, state);
+            let token = Some(cleaned).filter(|token| !token.is_empty()).and_then(|token| filters.hooks.on_token(token));
+            match token {
+                Some(token) if !token.is_empty() => vec![StreamEvent::Token(token)],
+                _ => return Vec::new(),
+            }
+        }
+        Chunk::Done(usage) => vec![StreamEvent::Done(usage)],
+    };
+    match tools {
+        Some(tools) => tools.process(events),
+        None => events,
+    }
+}
+
+/// **Chat stream with efficient processing**
+///
+/// When a session is given, the assembled assistant reply is appended to it once the stream completes.
+/// `permit` is released when the relay task ends, i.e. when the backend finishes or the client goes away.
+async fn chat_stream(
+    state: &AppState,
+    mut request: ChatRequest,
+    session: Option<SessionHandle>,
+    permit: GenerationPermit,
+) -> EventStream {
+    // Tokens are always relayed incrementally, whatever the caller asked for
+    request.stream = true;
+
+    let backend = match state.backend_for(&request.model, request.provider) {
+        Ok(backend) => backend,
+        Err(error) => return Box::pin(tokio_stream::once(StreamEvent::Error(error))),
+    };
+    if let Err(error) = state.hooks.before_request(&mut request.messages) {
+        return Box::pin(tokio_stream::once(StreamEvent::Error(error)));
+    }
+    fit_context(&state.config, &mut request);
+    let span = tracing::info_span!(
+        "chat_stream",
+        backend = backend.name(),
+        model = %request.model,
+        messages = request.messages.len(),
+    );
+    let stops = stop_sequences(request.options.as_ref());
+    let rules = state.cleaning_rules.for_model(&request.model);
+    let task_state = state.clone();
+    // Sent from the relay task, so with a worker pool Ollama isn't asked until a worker is free
+    let chunks = async move {
+        let state = task_state;
+        let cache = state.response_cache.as_ref().and_then(|cache| Some((cache, ResponseCache::key(&request)?)));
+        if let Some(chunks) = cache.as_ref().and_then(|(cache, key)| cache.get(key)) {
+            debug!("Serving the reply from the response cache");
+            state.metrics.cache_hit();
+            return Ok(chunks);
+        }
+        state.metrics.chat_request();
+        let mut chunks = backend.chat(&request).await.inspect_err(|error| {
+            state.metrics.upstream_failure(failure_kind(error));
+        })?;
+        let reconnects = state.config.stream_reconnects;
+        if reconnects > 0 && request.options.as_ref().is_some_and(ChatOptions::is_deterministic) {
+            chunks = backend::resume_on_disconnect(backend.clone(), request.clone(), chunks, reconnects);
+        }
+        Ok(match cache {
+            Some((cache, key)) => cache.clone().record(key, chunks),
+            None => chunks,
+        })
+    };
+    spawn_relay(state, chunks, session, permit, rules, stops, span)
+}
+
+/// `chat_stream` for `POST /generate`: the same cleaning and relaying over a bare completion
+async fn generate_stream(state: &AppState, mut request: GenerateRequest, permit: GenerationPermit) -> EventStream {
+    request.stream = true;
+
+    let backend = match state.backend_for(&request.model, request.provider) {
+        Ok(backend) => backend,
+        Err(error) => return Box::pin(tokio_stream::once(StreamEvent::Error(error))),
+    };
+    // Hooks see the prompt as the one user message it amounts to
+    let mut messages = vec![Message { role: "user".to_string(), content: request.prompt, images: Vec::new() }];
+    if let Err(error) = state.hooks.before_request(&mut messages) {
+        return Box::pin(tokio_stream::once(StreamEvent::Error(error)));
+    }
+    request.prompt = messages.into_iter().map(|message| message.content).collect::<Vec<_>>().join("\n\n");
+    let span = tracing::info_span!("generate_stream", backend = backend.name(), model = %request.model);
+    let stops = stop_sequences(request.options.as_ref());
+    let rules = state.cleaning_rules.for_model(&request.model);
+    let metrics = state.metrics.clone();
+    let chunks = async move {
+        metrics.chat_request();
+        backend.generate(&request).await.inspect_err(|error| metrics.upstream_failure(failure_kind(error)))
+    };
+    spawn_relay(state, chunks, None, permit, rules, stops, span)
+}
+
+fn stop_sequences(options: Option<&ChatOptions>) -> Option<StopSequences> {
+    StopSequences::new(options?.stop.as_deref()?)
+}
+
+/// Start the backend call `chunks` and relay what it streams from a task of its own, or from the
+/// worker pool when there is one, returning the stream of events sent. The task holds `permit`
+/// and counts as an active stream until it ends, including while it waits for a worker.
+fn spawn_relay(
+    state: &AppState,
+    chunks: impl Future<Output = Result<ChunkStream, AppError>> + Send + 'static,
+    session: Option<SessionHandle>,
+    permit: GenerationPermit,
+    rules: Arc<CleaningRules>,
+    stops: Option<StopSequences>,
+    span: tracing::Span,
+) -> EventStream {
+    let (tx, rx) = mpsc::channel(state.config.stream_buffer);
+    let guard = ActiveStreamGuard::new(state, Instant::now());
+    let filters = TokenFilters { rules, hooks: state.hooks.clone() };
+    let metrics = state.metrics.clone();
+    let tools = state.config.forward_tool_calls.then(ToolCallParser::default);
+    let idle_timeout = state.config.token_idle_timeout;
+
+    let (abort, registration) = future::AbortHandle::new_pair();
+    let in_flight = state.in_flight.register(abort.clone());
+    let task = future::Abortable::new(async move {
+        let _guard = guard;
+        let _permit = permit;
+        let _in_flight = in_flight;
+        let panic_tx = tx.clone();
+        let run = async move {
+            match chunks.await {
+                Ok(mut chunks) => {
+                    if let Some(idle) = idle_timeout {
+                        chunks = backend::end_when_idle(chunks, idle);
+                    }
+                    relay(chunks, tx, session, filters, metrics, tools, stops).await
+                }
+                Err(error) => {
+                    let _ = tx.send(StreamEvent::Error(error)).await;
+                }
+            }
+        };
+        // A bug in the relay (or a hook) ends this stream with an error frame, rather than a stream
+        // that just stops, or a pool worker gone for good
+        if let Err(panic) = std::panic::AssertUnwindSafe(run).catch_unwind().await {
+            error!(panic = panic_message(&*panic), "Stream task panicked");
+            let error = AppError::Internal("the stream failed unexpectedly".to_string());
+            let _ = panic_tx.send(StreamEvent::Error(error)).await;
+        }
+    }.instrument(span), registration);
+    let task = task.map(drop).boxed();
+    match &state.workers {
+        Some(workers) => {
+            if let Err(error) = workers.submit(task) {
+                return Box::pin(tokio_stream::once(StreamEvent::Error(error)));
+            }
+        }
+        None => {
+            tokio::spawn(task);
+        }
+    }
+
+    // Dropping the stream (a client hanging up, a WebSocket `stop`) aborts the task right away
+    // rather than whenever the relay next notices the channel closed. A job still queued for a
+    // worker ends as soon as one picks it up.
+    let mut relay_task = RelayTask { abort, finished: false };
+    Box::pin(ReceiverStream::new(rx).map(move |event| relay_task.observe(event)))
+}
+
+/// The spawned relay of one `chat_stream`, aborted if its stream is dropped before the end
+struct RelayTask {
+    abort: future::AbortHandle,
+    /// The last event was seen: the relay is only recording the reply now, so let it finish
+    finished: bool,
+}
+
+impl RelayTask {
+    fn observe(&mut self, event: StreamEvent) -> StreamEvent {
+        if matches!(event, StreamEvent::Done(_) | StreamEvent::Error(_)) {
+            self.finished = true;
+        }
+        event
+    }
+}
+
+impl Drop for RelayTask {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.abort.abort();
+        }
+    }
+}
+
+/// Metrics label for an error returned by `ChatBackend::chat`, i.e. before any output
+fn failure_kind(error: &AppError) -> &'static str {
+    match error {
+        AppError::Timeout(_) => "timeout",
+        AppError::UpstreamUnreachable(_) => "connect",
+        _ => "status",
+    }
+}
+
+type EventSender = mpsc::Sender<StreamEvent>;
+
+/// Relay the backend's chunks into `tx` until they end, fail, or the client goes away.
+/// Returning drops `chunks`, which closes the upstream connection and stops the generation.
+async fn relay(
+    mut chunks: ChunkStream,
+    tx: EventSender,
+    session: Option<SessionHandle>,
+    filters: TokenFilters,
+    metrics: Arc<Metrics>,
+    mut tools: Option<ToolCallParser>,
+    mut stops: Option<StopSequences>,
+) {
+    let started = Instant::now();
+    let mut tokens = 0usize;
+    let mut clean_state = CleanState::default();
+    let mut reply = String::new();
+    let mut usage = None;
+
+    loop {
+        // Also watch for the client leaving while the model is still thinking, so a slow
+        // first token doesn't keep the generation (and its slot) alive for nobody
+        let chunk = tokio::select! {
+            chunk = chunks.next() => chunk,
+            _ = tx.closed() => {
+                debug!(tokens, "Client went away, dropping the upstream response");
+                return;
+            }
+        };
+        let mut failure = None;
+        let (events, ended) = match chunk {
+            Some(Ok(chunk)) => (clean_chunk(chunk, &filters, &mut clean_state, tools.as_mut()), false),
+            // Timed out or lost the connection part way through: flush what's held back, then
+            // say so, so a truncated answer doesn't pass for a complete one
+            Some(Err(error)) => {
+                warn!(tokens, elapsed_ms = started.elapsed().as_millis() as u64, %error, "Backend stream failed");
+                let kind = match error {
+                    AppError::Timeout(_) => "timeout",
+                    AppError::Stalled(_) => "stalled",
+                    _ => "stream",
+                };
+                metrics.upstream_failure(kind);
+                failure = Some(error);
+                (tools.as_mut().map(ToolCallParser::flush).unwrap_or_default(), true)
+            }
+            // Ended without `done`: flush anything the tool parser is holding back
+            None => (tools.as_mut().map(ToolCallParser::flush).unwrap_or_default(), true),
+        };
+
+        let mut events = match &mut stops {
+            Some(stops) => {
+                let mut events = stops.process(events);
+                if ended {
+                    events.extend(stops.flush());
+                }
+                events
+            }
+            None => events,
+        };
+        let failed = failure.is_some();
+        events.extend(failure.map(StreamEvent::Error));
+
+        // A stop sequence ends the stream here too; dropping `chunks` then stops the generation
+        let finished = take_usage(&events, &mut usage) || ended;
+        if let Err(err) = forward(&tx, events, &mut reply, &mut tokens).await {
+            debug!(tokens, %err, "Dropping the upstream response");
+            return;
+        }
+        if failed {
+            // Keep the partial reply out of the session, so the next turn doesn't build on it
+            return;
+        }
+        if finished {
+            break;
+        }
+    }
+    // Done with the backend: let go of the connection now rather than after the bookkeeping below
+    drop(chunks);
+
+    // Ollama's `eval_count` counts model tokens; ours counts chunks, which is close but not exact
+    let elapsed = started.elapsed();
+    let done_reason = usage.as_ref().and_then(|usage| usage.done_reason.clone());
+    let generated = usage.and_then(|usage| usage.completion_tokens).unwrap_or(tokens as u64);
+    let tokens_per_second = (elapsed > Duration::ZERO).then(|| generated as f64 / elapsed.as_secs_f64());
+    if let Some(rate) = tokens_per_second {
+        metrics.tokens_per_second(rate);
+    }
+    info!(
+        tokens,
+        generated,
+        elapsed_ms = elapsed.as_millis() as u64,
+        tokens_per_second = tokens_per_second.map(|rate| (rate * 10.0).round() / 10.0),
+        ?done_reason,
+        "Stream complete"
+    );
+
+    if let Some(session) = session {
+        if !reply.is_empty() {
+            session.record_reply(reply).await;
+        }
+    }
+}
+
+/// Whether `events` include the final `Done`, keeping its usage
+fn take_usage(events: &[StreamEvent], usage: &mut Option<Usage>) -> bool {
+    events.iter().any(|event| match event {
+        StreamEvent::Done(done) => {
+            *usage = Some(done.clone());
+            true
+        }
+        _ => false,
+    })
+}
+
+/// Send `events` to the client while assembling the reply; `ClientGone` once the receiver has been dropped.
+///
+/// A full channel makes `send` wait, which stops reading from the backend until the client
+/// catches up: a slow reader delays tokens but never loses any.
+async fn forward(
+    tx: &EventSender,
+    events: Vec<StreamEvent>,
+    reply: &mut String,
+    tokens: &mut usize,
+) -> Result<(), AppError> {
+    for event in events {
+        let is_token = matches!(event, StreamEvent::Token(_));
+        if let StreamEvent::Token(token) = &event {
+            reply.push_str(token);
+        }
+        if tx.send(event).await.is_err() {
+            // The reply is abandoned with the client, so it never reaches the session
+            return Err(AppError::ClientGone);
+        }
+        if is_token {
+            *tokens += 1;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::post;
+
+    /// Serve the whole app, middleware and all, on an ephemeral port and return its base URL
+    async fn spawn_app(state: AppState) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, app(state).into_make_service_with_connect_info::<SocketAddr>()).await.unwrap()
+        });
+        format!("http://{}", addr)
+    }
+
+    /// Serve `router` on an ephemeral port and return its base URL
+    async fn spawn_upstream(router: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    async fn test_state(ollama_url: &str) -> AppState {
+        test_state_with(ollama_url, &[]).await
+    }
+
+    async fn test_state_with(ollama_url: &str, args: &[&str]) -> AppState {
+        let base = ["chatbot_api", "--ollama-url", ollama_url];
+        AppState::new(Config::parse_from(base.iter().chain(args))).await
+    }
+
+    fn user_request(prompt: &str) -> ChatRequest {
+        ChatRequest {
+            model: "mistral".to_string(),
+            messages: vec![Message { role: "user".to_string(), content: prompt.to_string(), images: Vec::new() }],
+            stream: true,
+            system: None,
+            options: None,
+            keep_alive: None,
+            format: None,
+            provider: None,
+        }
+    }
+
+    /// One line of Ollama's `/api/chat` stream
+    fn ndjson_line(content: &str, done: bool) -> String {
+        let line = serde_json::json!({
+            "model": "mistral",
+            "created_at": "2024-01-01T00:00:00Z",
+            "message": { "role": "assistant", "content": content },
+            "done": done,
+        });
+        format!("{}\n", line)
+    }
+
+    /// An upstream that answers every chat request with `body`
+    async fn canned_upstream(body: String) -> String {
+        let handler = move || {
+            let body = body.clone();
+            async move { body }
+        };
+        spawn_upstream(Router::new().route("/api/chat", post(handler))).await
+    }
+
+    /// An upstream that streams `chunks` as separate body frames, pausing between them so they arrive apart
+    async fn chunked_upstream(chunks: Vec<Vec<u8>>) -> String {
+        let handler = move || {
+            let chunks = chunks.clone();
+            async move {
+                let body = async_stream::stream! {
+                    for chunk in chunks {
+                        yield Ok::<_, std::io::Error>(chunk);
+                        tokio::time::sleep(Duration::from_millis(1)).await;
+                    }
+                };
+                axum::body::Body::from_stream(body)
+            }
+        };
+        spawn_upstream(Router::new().route("/api/chat", post(handler))).await
+    }
+
+    /// Run one chat against `ollama_url`, returning the joined tokens and the final event
+    async fn collect_chat(ollama_url: &str) -> (String, Option<StreamEvent>) {
+        let state = test_state(ollama_url).await;
+        let mut stream = chat_stream(&state, user_request("hi"), None, None).await;
+        let mut text = String::new();
+        while let Some(event) = stream.next().await {
+            match event {
+                StreamEvent::Token(token) => text.push_str(&token),
+                last => return (text, Some(last)),
+            }
+        }
+        (text, None)
+    }
+
+    async fn body_text(response: Response) -> String {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn plain_text_stream_joins_tokens_exactly() {
+        let tokens = ["Hello", ",", " wor", "ld", "!", " How", "'s", " it", " going", "?"];
+        let mut body: String = tokens.iter().map(|token| ndjson_line(token, false)).collect();
+        body.push_str(&ndjson_line("", true));
+        let state = test_state(&canned_upstream(body).await).await;
+
+        let response = stream_chat_response(&state, user_request("hi"), None, false).await;
+
+        assert_eq!(body_text(response).await, "Hello, world! How's it going?");
+    }
+
+    #[tokio::test]
+    async fn done_frame_ends_the_text_with_the_assembled_reply() {
+        let body = [ndjson_line("Hello", false), ndjson_line(", world", false), ndjson_line("", true)].concat();
+        let state = test_state(&canned_upstream(body).await).await;
+
+        let text = body_text(stream_chat_response(&state, user_request("hi"), None, true).await).await;
+
+        let (reply, frame) = text.split_once('\n').unwrap();
+        assert_eq!(reply, "Hello, world");
+        let frame: serde_json::Value = serde_json::from_str(frame.trim_end()).unwrap();
+        assert_eq!(frame["done"], true);
+        assert_eq!(frame["content"], "Hello, world");
+    }
+
+    #[tokio::test]
+    async fn objects_split_across_chunks_are_reassembled() {
+        let mut body = String::new();
+        for token in ["Café", " ☕", "  and", "\n\n", "crème[control_9]", " brûlée<unk>"] {
+            body.push_str(&ndjson_line(token, false));
+        }
+        body.push_str(&ndjson_line("", true));
+        // 3-byte chunks split both JSON objects and multi-byte characters
+        let chunks = body.as_bytes().chunks(3).map(<[u8]>::to_vec).collect();
+
+        let (text, last) = collect_chat(&chunked_upstream(chunks).await).await;
+
+        assert_eq!(text, "Café ☕ and\n\ncrème brûlée");
+        assert!(matches!(last, Some(StreamEvent::Done(_))));
+    }
+
+    #[tokio::test]
+    async fn several_objects_in_one_chunk_all_count() {
+        let lines: String = ["One", " two", " three"].iter().map(|token| ndjson_line(token, false)).collect();
+        let chunks = vec![lines.into_bytes(), ndjson_line(" four", false).into_bytes(), ndjson_line("", true).into_bytes()];
+
+        let (text, last) = collect_chat(&chunked_upstream(chunks).await).await;
+
+        assert_eq!(text, "One two three four");
+        assert!(matches!(last, Some(StreamEvent::Done(_))));
+    }
+
+    #[tokio::test]
+    async fn a_failed_turn_leaves_the_session_history_untouched() {
+        let (sent, mut received) = mpsc::unbounded_channel();
+        let upstream = move |Json(request): Json<serde_json::Value>| {
+            let messages = request["messages"].as_array().unwrap().clone();
+            let fail = messages.last().is_some_and(|message| message["content"] == "fail");
+            let _ = sent.send(messages.len());
+            async move {
+                if fail {
+                    (StatusCode::NOT_FOUND, serde_json::json!({ "error": "no such model" }).to_string())
+                } else {
+                    (StatusCode::OK, [ndjson_line("Hi", false), ndjson_line("", true)].concat())
+                }
+            }
+        };
+        let state = test_state(&spawn_upstream(Router::new().route("/api/chat", post(upstream))).await).await;
+        let base = spawn_app(state.clone()).await;
+        let client = Client::new();
+        let chat = |prompt: &str| client.get(format!("{}/chat?prompt={}&session_id=s", base, prompt)).send();
+
+        assert_eq!(chat("fail").await.unwrap().status(), StatusCode::NOT_FOUND);
+        assert!(state.sessions.history("s").await.is_empty(), "the failed prompt was kept");
+
+        // The retry goes out on its own, not after the prompt that failed
+        assert_eq!(chat("hi").await.unwrap().text().await.unwrap(), "Hi");
+        assert_eq!(received.recv().await, Some(1));
+        assert_eq!(received.recv().await, Some(1));
+        let roles: Vec<_> = state.sessions.history("s").await.into_iter().map(|message| message.role).collect();
+        assert_eq!(roles, ["user", "assistant"]);
+    }
+
+    #[tokio::test]
+    async fn a_turn_finishing_during_regeneration_is_kept() {
+        let slow = || async {
+            let body = async_stream::stream! {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                yield Ok::<_, std::io::Error>(ndjson_line("stale", false));
+                yield Ok(ndjson_line("", true));
+            };
+            axum::body::Body::from_stream(body)
+        };
+        let state = test_state(&spawn_upstream(Router::new().route("/api/chat", post(slow))).await).await;
+        let message = |role: &str, content: &str| {
+            Message { role: role.to_string(), content: content.to_string(), images: Vec::new() }
+        };
+        state.sessions.record_turn("s", message("user", "hi"), message("assistant", "hello")).await;
+        let base = spawn_app(state.clone()).await;
+
+        let regenerate = tokio::spawn(
+            Client::new().post(format!("{}/regenerate", base)).json(&serde_json::json!({ "session_id": "s" })).send(),
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        state.sessions.record_turn("s", message("user", "and now?"), message("assistant", "newer")).await;
+
+        assert_eq!(regenerate.await.unwrap().unwrap().status(), StatusCode::BAD_REQUEST);
+        let history = state.sessions.history("s").await;
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[3].content, "newer");
+    }
+
+    #[tokio::test]
+    async fn errors_ollama_sends_mid_stream_end_it_with_their_reason() {
+        let error = serde_json::json!({ "error": "model ran out of memory" });
+        let body = format!("{}{}\n", ndjson_line("Hel", false), error);
+        let (text, last) = collect_chat(&canned_upstream(body).await).await;
+
+        assert_eq!(text, "Hel");
+        match last {
+            Some(StreamEvent::Error(error)) => {
+                assert!(error.to_string().ends_with("model ran out of memory"), "{}", error)
+            }
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn cors_origins_come_from_the_config() {
+        let args = ["--cors-allowed-origins", "https://chat.example.com/, "];
+        let base = spawn_app(test_state_with("http://127.0.0.1:9", &args).await).await;
+        let allowed_origin = |origin: &'static str| {
+            let request = Client::new().get(format!("{}/health", base)).header("origin", origin).send();
+            async move { request.await.unwrap().headers().get("access-control-allow-origin").cloned() }
+        };
+
+        assert_eq!(allowed_origin("https://chat.example.com").await.unwrap(), "https://chat.example.com");
+        assert!(allowed_origin("https://evil.example.com").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn title_bodies_are_validated_like_chat_bodies() {
+        let reply = [ndjson_line("A title", false), ndjson_line("", true)].concat();
+        let state = test_state_with(&canned_upstream(reply).await, &["--max-prompt-chars", "100"]).await;
+        let base = spawn_app(state).await;
+        let client = Client::new();
+        let title = |messages: serde_json::Value| {
+            client.post(format!("{}/title", base)).json(&serde_json::json!({ "messages": messages })).send()
+        };
+
+        let response = title(serde_json::json!([{ "role": "robot", "content": "hi" }])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = title(serde_json::json!([{ "role": "user", "content": "x".repeat(101) }])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let response = title(serde_json::json!([{ "role": "user", "content": "hi" }])).await.unwrap();
+        assert_eq!(response.json::<serde_json::Value>().await.unwrap()["title"], "A title");
+    }
+
+    #[tokio::test]
+    async fn upstream_error_status_is_reported() {
+        let not_found = || async {
+            let body = serde_json::json!({ "error": "model \"nope\" not found, try pulling it first" });
+            (StatusCode::NOT_FOUND, Json(body))
+        };
+        let upstream = spawn_upstream(Router::new().route("/api/chat", post(not_found))).await;
+
+        let (text, last) = collect_chat(&upstream).await;
+
+        assert_eq!(text, "");
+        match last {
+            Some(StreamEvent::Error(error)) => {
+                assert_eq!(error.status(), StatusCode::NOT_FOUND);
+                assert_eq!(error.to_string(), "model not found: model \"nope\" not found, try pulling it first");
+            }
+            other => panic!("expected an error event, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_consumers_get_every_token() {
+        let tokens: Vec<String> = (0..200).map(|i| format!("t{} ", i)).collect();
+        let mut body: String = tokens.iter().map(|token| ndjson_line(token, false)).collect();
+        body.push_str(&ndjson_line("", true));
+        // The smallest buffer, so the relay is blocked on a full channel for nearly every token
+        let state = test_state_with(&canned_upstream(body).await, &["--stream-buffer", "1"]).await;
+
+        let mut stream = chat_stream(&state, user_request("hi"), None, None).await;
+        let mut received = Vec::new();
+        while let Some(event) = stream.next().await {
+            match event {
+                StreamEvent::Token(token) => received.push(token),
+                StreamEvent::Done(_) => break,
+                other => panic!("unexpected event: {:?}", other),
+            }
+            if received.len() % 20 == 0 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+
+        // Whitespace collapsing keeps each token's single trailing space
+        assert_eq!(received, tokens);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_client_stream_stops_the_relay() {
+        // An upstream that never finishes generating
+        let endless = || async {
+            let body = async_stream::stream! {
+                loop {
+                    yield Ok::<_, std::io::Error>(ndjson_line("tick", false));
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            };
+            axum::body::Body::from_stream(body)
+        };
+        let upstream = spawn_upstream(Router::new().route("/api/chat", post(endless))).await;
+        let state = test_state(&upstream).await;
+
+        let mut stream = chat_stream(&state, user_request("hi"), None, None).await;
+        assert!(matches!(stream.next().await, Some(StreamEvent::Token(_))));
+        assert_eq!(state.active_streams.load(Ordering::SeqCst), 1);
+        drop(stream);
+
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while state.active_streams.load(Ordering::SeqCst) > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("relay kept sending after the client went away");
+    }
+
+    #[tokio::test]
+    async fn dropping_the_stream_aborts_a_stalled_generation() {
+        // Sends one token, then goes quiet for good
+        let stalled = || async {
+            let body = async_stream::stream! {
+                yield Ok::<_, std::io::Error>(ndjson_line("thinking", false));
+                std::future::pending::<()>().await;
+            };
+            axum::body::Body::from_stream(body)
+        };
+        let state = test_state(&spawn_upstream(Router::new().route("/api/chat", post(stalled))).await).await;
+
+        let mut stream = chat_stream(&state, user_request("hi"), None, None).await;
+        assert!(matches!(stream.next().await, Some(StreamEvent::Token(_))));
+        drop(stream);
+
+        tokio::time::timeout(Duration::from_millis(500), async {
+            while state.active_streams.load(Ordering::SeqCst) > 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("relay task outlived its stream");
+    }
+
+    #[tokio::test]
+    async fn a_model_that_goes_silent_gets_a_stalled_error() {
+        let silent = || async {
+            let body = async_stream::stream! {
+                yield Ok::<_, std::io::Error>(ndjson_line("thinking", false));
+                std::future::pending::<()>().await;
+            };
+            axum::body::Body::from_stream(body)
+        };
+        let upstream = spawn_upstream(Router::new().route("/api/chat", post(silent))).await;
+        let state = test_state_with(&upstream, &["--token-idle-timeout-secs", "1"]).await;
+
+        let (text, last) = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut stream = chat_stream(&state, user_request("hi"), None, None).await;
+            let mut text = String::new();
+            while let Some(event) = stream.next().await {
+                match event {
+                    StreamEvent::Token(token) => text.push_str(&token),
+                    last => return (text, Some(last)),
+                }
+            }
+            (text, None)
+        })
+        .await
+        .expect("the silent stream was never ended");
+
+        assert_eq!(text, "thinking");
+        assert!(matches!(last, Some(StreamEvent::Error(AppError::Stalled(_)))), "got {:?}", last);
+    }
+
+    #[tokio::test]
+    async fn mock_backend_streams_a_canned_reply_without_ollama() {
+        // Nothing listens there: any request reaching Ollama would fail
+        let state = test_state_with("http://127.0.0.1:9", &["--mock-backend"]).await;
+
+        let mut stream = chat_stream(&state, user_request("ping"), None, None).await;
+        let mut tokens = Vec::new();
+        let last = loop {
+            match stream.next().await {
+                Some(StreamEvent::Token(token)) => tokens.push(token),
+                last => break last,
+            }
+        };
+
+        assert!(matches!(last, Some(StreamEvent::Done(_))), "got {:?}", last);
+        assert!(tokens.len() > 10, "the reply wasn't streamed token by token");
+        assert!(tokens.concat().contains("> ping"));
+    }
+
+    #[tokio::test]
+    async fn upstream_stops_being_read_once_the_consumer_drops() {
+        // Streams tokens for as long as anyone reads them, counting each one written
+        struct Closed(Arc<std::sync::atomic::AtomicBool>);
+        impl Drop for Closed {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+        let written = Arc::new(AtomicUsize::new(0));
+        let closed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let endless = {
+            let (written, closed) = (written.clone(), closed.clone());
+            move || {
+                let (written, closed) = (written.clone(), Closed(closed.clone()));
+                async move {
+                    let body = async_stream::stream! {
+                        let _closed = closed;
+                        loop {
+                            written.fetch_add(1, Ordering::SeqCst);
+                            yield Ok::<_, std::io::Error>(ndjson_line("token ", false));
+                            tokio::time::sleep(Duration::from_millis(5)).await;
+                        }
+                    };
+                    axum::body::Body::from_stream(body)
+                }
+            }
+        };
+        let state = test_state(&spawn_upstream(Router::new().route("/api/chat", post(endless))).await).await;
+
+        let mut stream = chat_stream(&state, user_request("hi"), None, None).await;
+        for _ in 0..3 {
+            assert!(matches!(stream.next().await, Some(StreamEvent::Token(_))));
+        }
+        drop(stream);
+
+        // The upstream only drops its body once writing to the connection fails, i.e. once it's closed
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !closed.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("upstream connection was kept open after the consumer dropped");
+        let after_close = written.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(written.load(Ordering::SeqCst), after_close);
+    }
+
+    #[tokio::test]
+    async fn sse_sends_each_token_as_its_own_event() {
+        let tokens = ["Hello", " wor", "ld", "\n\n", "line\n", "  indented", " end"];
+        let mut body: String = tokens.iter().map(|token| ndjson_line(token, false)).collect();
+        body.push_str(&ndjson_line("", true));
+        let base = spawn_app(test_state(&canned_upstream(body).await).await).await;
+
+        let raw = Client::new().get(format!("{}/chat/sse?prompt=hi", base)).send().await.unwrap().text().await.unwrap();
+
+        // Decode the way `EventSource` does: one optional space after `data:`, lines joined with `\n`
+        let data: Vec<String> = raw
+            .split("\n\n")
+            .filter(|event| !event.is_empty() && !event.starts_with("event:"))
+            .map(|event| {
+                let lines = event.lines().filter_map(|line| line.strip_prefix("data:"));
+                lines.map(|line| line.strip_prefix(' ').unwrap_or(line)).collect::<Vec<_>>().join("\n")
+            })
+            .collect();
+        assert_eq!(data, tokens);
+    }
+
+    #[tokio::test]
+    async fn compressed_streams_still_arrive_progressively() {
+        // Holds the generation open after the first token until the test has received it
+        let (release, held) = tokio::sync::watch::channel(false);
+        let upstream = move || {
+            let mut held = held.clone();
+            async move {
+                let body = async_stream::stream! {
+                    yield Ok::<_, std::io::Error>(ndjson_line("Hello", false));
+                    let _ = held.wait_for(|released| *released).await;
+                    yield Ok(ndjson_line(" world", false));
+                    yield Ok(ndjson_line("", true));
+                };
+                axum::body::Body::from_stream(body)
+            }
+        };
+        let state = test_state(&spawn_upstream(Router::new().route("/api/chat", post(upstream))).await).await;
+        let base = spawn_app(state).await;
+
+        let mut response = Client::new()
+            .get(format!("{}/chat?prompt=hi", base))
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let first = tokio::time::timeout(Duration::from_secs(2), response.chunk())
+            .await
+            .expect("compressed body was buffered instead of streamed");
+        assert!(!first.unwrap().unwrap().is_empty());
+
+        release.send(true).unwrap();
+        while response.chunk().await.unwrap().is_some() {}
+    }
+
+    #[tokio::test]
+    async fn prompts_that_are_not_utf8_are_rejected() {
+        let upstream = canned_upstream(format!("{}{}", ndjson_line("Bonjour", false), ndjson_line("", true))).await;
+        let base = spawn_app(test_state(&upstream).await).await;
+        let client = Client::new();
+
+        // `%FF%FE` decodes to bytes, but not to text
+        let response = client.get(format!("{}/chat?prompt=%FF%FE", base)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], 400);
+        assert!(body["error"]["message"].as_str().unwrap().contains("\"prompt\""), "unexpected error: {}", body);
+
+        // Percent-encoded UTF-8 still decodes, `+` included
+        let response = client.get(format!("{}/chat?prompt=caf%C3%A9+au+lait&stream=false", base)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// Blocks prompts mentioning "forbidden", and shouts every token except "world"
+    struct TestHook;
+
+    impl hooks::ChatHook for TestHook {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        fn before_request(&self, messages: &mut Vec<Message>) -> Result<(), AppError> {
+            if messages.iter().any(|message| message.content.contains("forbidden")) {
+                return Err(AppError::Forbidden("prompt rejected by policy".to_string()));
+            }
+            messages.push(Message { role: "user".to_string(), content: "be brief".to_string(), images: Vec::new() });
+            Ok(())
+        }
+
+        fn on_token(&self, token: String) -> Option<String> {
+            (token.trim() != "world").then(|| token.to_uppercase())
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks_rewrite_prompts_and_tokens() {
+        let (prompts, mut sent) = mpsc::unbounded_channel();
+        let upstream = move |Json(request): Json<serde_json::Value>| {
+            let _ = prompts.send(request["messages"].as_array().unwrap().len());
+            async { format!("{}{}{}", ndjson_line("Hello", false), ndjson_line(" world", false), ndjson_line("!", true)) }
+        };
+        let mut state = test_state(&spawn_upstream(Router::new().route("/api/chat", post(upstream))).await).await;
+        state.hooks = Arc::new(Hooks::default().with(TestHook));
+        let base = spawn_app(state).await;
+        let client = Client::new();
+
+        let response = client.get(format!("{}/chat?prompt=hi", base)).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "HELLO!");
+        assert_eq!(sent.recv().await, Some(2), "the hook's extra message wasn't sent");
+
+        let response = client.get(format!("{}/chat?prompt=something+forbidden", base)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(sent.try_recv().is_err(), "a rejected prompt reached the model");
+    }
+
+    #[tokio::test]
+    async fn api_key_and_session_budgets_both_apply() {
+        let reply = [ndjson_line("Hi", false), ndjson_line("", true)].concat();
+        let args = ["--session-rate-limit", "2", "--api-keys", "key-1,key-2,key-3"];
+        let state = test_state_with(&canned_upstream(reply).await, &args).await;
+        let base = spawn_app(state).await;
+        let client = Client::new();
+        let chat = |query: String, key: Option<&str>| {
+            let request = client.get(format!("{}/chat?prompt=hi&{}", base, query));
+            let request = match key {
+                Some(key) => request.bearer_auth(key),
+                None => request,
+            };
+            async move { request.send().await.unwrap() }
+        };
+        let rejected_for = |response: reqwest::Response| async move {
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            let body: serde_json::Value = response.json().await.unwrap();
+            body["error"]["message"].as_str().unwrap().to_string()
+        };
+
+        // A new session each time doesn't reset the key's budget
+        for session in ["a", "b"] {
+            assert!(chat(format!("session_id={}", session), Some("key-1")).await.status().is_success());
+        }
+        let response = chat("session_id=c".to_string(), Some("key-1")).await;
+        assert_eq!(rejected_for(response).await, "rate limit exceeded for this API key, slow down");
+
+        // Nor does another key reset the session's
+        for key in ["key-2", "key-3"] {
+            assert!(chat("session_id=shared".to_string(), Some(key)).await.status().is_success());
+        }
+        let response = chat("session_id=shared".to_string(), Some("key-2")).await;
+        assert_eq!(rejected_for(response).await, "rate limit exceeded for this session, slow down");
+    }
+
+    #[tokio::test]
+    async fn turned_away_requests_cost_no_budget() {
+        let reply = [ndjson_line("Hi", false), ndjson_line("", true)].concat();
+        let args = ["--session-rate-limit", "1", "--api-keys", "key-1,key-2,key-3"];
+        let base = spawn_app(test_state_with(&canned_upstream(reply).await, &args).await).await;
+        let client = Client::new();
+        let chat = |session: &str, key: &str| {
+            let request = client.get(format!("{}/chat?prompt=hi&session_id={}", base, session)).bearer_auth(key);
+            async move { request.send().await.unwrap().status() }
+        };
+
+        // The session is out of budget, so key-2 isn't charged for the attempt
+        assert!(chat("a", "key-1").await.is_success());
+        assert_eq!(chat("a", "key-2").await, StatusCode::TOO_MANY_REQUESTS);
+        assert!(chat("b", "key-2").await.is_success());
+
+        // A made-up key is refused without spending the session it names
+        assert_eq!(chat("c", "guessed").await, StatusCode::UNAUTHORIZED);
+        assert!(chat("c", "key-3").await.is_success());
+    }
+
+    #[test]
+    fn tool_results_are_accepted_but_unknown_roles_are_not() {
+        let config = Config::parse_from(["chatbot_api"]);
+        let request = |roles: &[&str]| -> ChatRequest {
+            let messages: Vec<_> =
+                roles.iter().map(|role| serde_json::json!({ "role": role, "content": "x" })).collect();
+            serde_json::from_value(serde_json::json!({ "model": "mistral", "messages": messages })).unwrap()
+        };
+        assert!(validate_request(&config, &request(&["user", "assistant", "tool"])).is_ok());
+        assert!(validate_request(&config, &request(&["user", "robot"])).unwrap_err().contains("messages[1].role"));
+    }
+
+    #[test]
+    fn blank_generate_prompts_are_rejected() {
+        let config = Config::parse_from(["chatbot_api"]);
+        let request = |prompt: &str| -> GenerateRequest {
+            serde_json::from_value(serde_json::json!({ "model": "mistral", "prompt": prompt })).unwrap()
+        };
+        for blank in ["", "   ", "\n\t "] {
+            assert!(matches!(validate_generate(&config, &request(blank)), Err(AppError::BadRequest(_))), "{:?}", blank);
+        }
+        assert!(validate_generate(&config, &request(" hi ")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn moderation_blocks_prompts_and_redacts_replies() {
+        let upstream = canned_upstream([ndjson_line("The secret", false), ndjson_line(" is out", true)].concat()).await;
+        let mut state = test_state(&upstream).await;
+        let moderation = Moderation::parse("# comments are skipped\n\n(?i)forbidden\nsecret\n", true).unwrap();
+        state.hooks = Arc::new(Hooks::default().with(moderation));
+        let base = spawn_app(state).await;
+        let client = Client::new();
+
+        let response = client.get(format!("{}/chat?prompt=something+FORBIDDEN", base)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = client.get(format!("{}/chat?prompt=hi", base)).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "The [redacted] is out");
+
+        assert!(Moderation::parse("ok\n(unclosed", false).unwrap_err().starts_with("line 2:"));
+    }
+
+    /// Panics on prompts mentioning "crash", and on the token " world"
+    struct PanickingHook;
+
+    impl hooks::ChatHook for PanickingHook {
+        fn name(&self) -> &'static str {
+            "panicking"
+        }
+
+        fn before_request(&self, messages: &mut Vec<Message>) -> Result<(), AppError> {
+            assert!(!messages.iter().any(|message| message.content.contains("crash")), "hook failed on the prompt");
+            Ok(())
+        }
+
+        fn on_token(&self, token: String) -> Option<String> {
+            assert_ne!(token, " world", "hook failed on a token");
+            Some(token)
+        }
+    }
+
+    #[tokio::test]
+    async fn panics_in_hooks_become_errors() {
+        let reply = format!("{}{}{}", ndjson_line("Hello", false), ndjson_line(" world", false), ndjson_line("", true));
+        let mut state = test_state(&canned_upstream(reply).await).await;
+        state.hooks = Arc::new(Hooks::default().with(PanickingHook));
+        let base = spawn_app(state).await;
+        let client = Client::new();
+
+        // In the stream task: the tokens so far, then an error line instead of the stream just stopping
+        let response = client.get(format!("{}/chat?prompt=hi", base)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.text().await.unwrap();
+        let (text, error) = body.split_once('\n').expect("no error line after the tokens");
+        assert_eq!(text, "Hello");
+        let error: serde_json::Value = serde_json::from_str(error.trim()).unwrap();
+        assert_eq!(error["error"]["code"], 500);
+
+        // In the handler: the usual JSON error, and the server carries on
+        let response = client.get(format!("{}/chat?prompt=crash", base)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], 500);
+        let response = client.get(format!("{}/stats", base)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn default_and_request_system_prompts_are_sent_as_one() {
+        let (prompts, mut sent) = mpsc::unbounded_channel();
+        let upstream = move |Json(request): Json<serde_json::Value>| {
+            let _ = prompts.send(request["messages"].clone());
+            async { format!("{}{}", ndjson_line("Bonjour", false), ndjson_line("", true)) }
+        };
+        let upstream = spawn_upstream(Router::new().route("/api/chat", post(upstream))).await;
+        let state = test_state_with(&upstream, &["--system-prompt", "You are helpful."]).await;
+        let base = spawn_app(state).await;
+
+        let body = serde_json::json!({
+            "messages": [
+                { "role": "system", "content": "Answer in French." },
+                { "role": "user", "content": "hi" },
+                { "role": "system", "content": "Be brief." },
+            ],
+        });
+        let response = Client::new().post(format!("{}/chat", base)).json(&body).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "Bonjour");
+
+        let messages = sent.recv().await.unwrap();
+        let roles: Vec<_> = messages.as_array().unwrap().iter().map(|message| message["role"].clone()).collect();
+        assert_eq!(roles, ["system", "user"]);
+        assert_eq!(messages[0]["content"], "You are helpful.\n\nAnswer in French.\n\nBe brief.");
+    }
+
+    #[tokio::test]
+    async fn streams_flush_incrementally_over_http2() {
+        // Holds the generation open after the first token until the test has received it
+        let (release, held) = tokio::sync::watch::channel(false);
+        let upstream = move || {
+            let mut held = held.clone();
+            async move {
+                let body = async_stream::stream! {
+                    yield Ok::<_, std::io::Error>(ndjson_line("Hello", false));
+                    let _ = held.wait_for(|released| *released).await;
+                    yield Ok(ndjson_line(" world", false));
+                    yield Ok(ndjson_line("", true));
+                };
+                axum::body::Body::from_stream(body)
+            }
+        };
+        let state = test_state(&spawn_upstream(Router::new().route("/api/chat", post(upstream))).await).await;
+        let base = spawn_app(state).await;
+
+        let client = Client::builder().http2_prior_knowledge().build().unwrap();
+        let mut response = client.get(format!("{}/chat?prompt=hi", base)).send().await.unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+
+        let first = tokio::time::timeout(Duration::from_secs(2), response.chunk())
+            .await
+            .expect("the HTTP/2 body was buffered instead of streamed");
+        assert_eq!(first.unwrap().unwrap(), "Hello");
+
+        release.send(true).unwrap();
+        let mut rest = Vec::new();
+        while let Some(chunk) = response.chunk().await.unwrap() {
+            rest.extend_from_slice(&chunk);
+        }
+        assert_eq!(rest, b" world");
+    }
+}
@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+use crate::{AppError, AppState};
+
+/// Seconds from sending a chat request to the end of its stream
+const STREAM_SECONDS_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+/// From CPU-bound large models up to small ones on a fast GPU
+const TOKENS_PER_SECOND_BUCKETS: &[f64] = &[1.0, 2.5, 5.0, 10.0, 20.0, 40.0, 80.0, 150.0, 300.0];
+
+/// Prometheus instruments, updated once per request or stream rather than per token
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    chat_requests: IntCounter,
+    cache_hits: IntCounter,
+    active_streams: IntGauge,
+    upstream_failures: IntCounterVec,
+    stream_seconds: Histogram,
+    tokens_per_second: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let chat_requests = IntCounter::new("chatbot_chat_requests_total", "Chat requests sent to Ollama").unwrap();
+        let cache_hits =
+            IntCounter::new("chatbot_response_cache_hits_total", "Chat requests answered from the response cache").unwrap();
+        let active_streams = IntGauge::new("chatbot_active_streams", "Streams currently being relayed").unwrap();
+        let upstream_failures = IntCounterVec::new(
+            Opts::new("chatbot_upstream_failures_total", "Chat requests Ollama failed to serve"),
+            &["kind"],
+        )
+        .unwrap();
+        let stream_seconds = Histogram::with_opts(
+            HistogramOpts::new("chatbot_stream_duration_seconds", "End-to-end duration of relayed streams")
+                .buckets(STREAM_SECONDS_BUCKETS.to_vec()),
+        )
+        .unwrap();
+        let tokens_per_second = Histogram::with_opts(
+            HistogramOpts::new("chatbot_tokens_per_second", "Generated tokens per second of wall-clock time, per stream")
+                .buckets(TOKENS_PER_SECOND_BUCKETS.to_vec()),
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(chat_requests.clone())).unwrap();
+        registry.register(Box::new(cache_hits.clone())).unwrap();
+        registry.register(Box::new(active_streams.clone())).unwrap();
+        registry.register(Box::new(upstream_failures.clone())).unwrap();
+        registry.register(Box::new(stream_seconds.clone())).unwrap();
+        registry.register(Box::new(tokens_per_second.clone())).unwrap();
+
+        Self {
+            registry,
+            chat_requests,
+            cache_hits,
+            active_streams,
+            upstream_failures,
+            stream_seconds,
+            tokens_per_second,
+        }
+    }
+
+    pub fn chat_request(&self) {
+        self.chat_requests.inc();
+    }
+
+    pub fn cache_hit(&self) {
+        self.cache_hits.inc();
+    }
+
+    /// `kind` is one of `connect`, `timeout`, `stalled`, `status`, or `stream`
+    pub fn upstream_failure(&self, kind: &str) {
+        self.upstream_failures.with_label_values(&[kind]).inc();
+    }
+
+    pub fn stream_started(&self) {
+        self.active_streams.inc();
+    }
+
+    pub fn stream_finished(&self, elapsed: Duration) {
+        self.active_streams.dec();
+        self.stream_seconds.observe(elapsed.as_secs_f64());
+    }
+
+    /// Throughput of one stream that ran to completion
+    pub fn tokens_per_second(&self, rate: f64) {
+        self.tokens_per_second.observe(rate);
+    }
+
+    fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer).unwrap_or_default())
+    }
+}
+
+/// `GET /metrics`: Prometheus text exposition format
+pub async fn metrics_handler(State(state): State<AppState>) -> Response {
+    match state.metrics.render() {
+        Ok(body) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response(),
+        Err(err) => AppError::Internal(format!("could not render metrics: {}", err)).into_response(),
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant};
+
+use futures::{future::BoxFuture, StreamExt};
+use serde_json::json;
+
+use crate::{
+    backend::{ChatBackend, Chunk, ChunkStream, LineStream},
+    context::estimate_tokens,
+    AppError, ChatRequest, GenerateRequest, KeepAlive, Message, Usage,
+};
+
+/// Roughly how long a small local model takes to load the prompt, then to produce each token
+const FIRST_TOKEN_DELAY: Duration = Duration::from_millis(400);
+const TOKEN_DELAY: Duration = Duration::from_millis(40);
+/// How much of the prompt the reply quotes back
+const ECHO_CHARS: usize = 80;
+
+/// `--mock-backend`: a canned reply for any model, streamed token by token at about the pace of a
+/// real one, so a frontend can be worked on without Ollama or a GPU. The tokens go through the
+/// same relay, cleaning and sessions as a real generation; only the model is missing.
+pub struct MockBackend;
+
+impl ChatBackend for MockBackend {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn chat<'a>(&'a self, request: &'a ChatRequest) -> BoxFuture<'a, Result<ChunkStream, AppError>> {
+        let prompt = request.messages.iter().rev().find(|message| message.role == "user");
+        let prompt_tokens = request.messages.iter().map(estimate_tokens).sum();
+        Box::pin(async move { Ok(mock_chunks(reply(prompt.map_or("", |message| &message.content)), prompt_tokens)) })
+    }
+
+    fn generate<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<ChunkStream, AppError>> {
+        let prompt = Message { role: "user".to_string(), content: request.prompt.clone(), images: Vec::new() };
+        Box::pin(async move { Ok(mock_chunks(reply(&request.prompt), estimate_tokens(&prompt))) })
+    }
+
+    /// Ollama's NDJSON lines, so `raw=true` clients see the shape they'd get from the real thing
+    fn chat_raw<'a>(&'a self, request: &'a ChatRequest) -> BoxFuture<'a, Result<LineStream, AppError>> {
+        let model = request.model.clone();
+        Box::pin(async move {
+            let chunks = self.chat(request).await?;
+            let lines = chunks.map(move |chunk| {
+                let line = match chunk? {
+                    Chunk::Content(content) => json!({
+                        "model": model,
+                        "message": { "role": "assistant", "content": content },
+                        "done": false,
+                    }),
+                    Chunk::Done(usage) => json!({
+                        "model": model,
+                        "message": { "role": "assistant", "content": "" },
+                        "done": true,
+                        "done_reason": usage.done_reason,
+                        "prompt_eval_count": usage.prompt_tokens,
+                        "eval_count": usage.completion_tokens,
+                    }),
+                };
+                Ok(line.to_string())
+            });
+            Ok(Box::pin(lines) as LineStream)
+        })
+    }
+
+    fn warm_up<'a>(&'a self, _model: &'a str, _keep_alive: Option<KeepAlive>) -> BoxFuture<'a, Result<(), AppError>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A reply with the markdown a chat UI has to render: emphasis, a list and a code block
+fn reply(prompt: &str) -> String {
+    let mut echo: String = prompt.chars().take(ECHO_CHARS).collect();
+    if echo.len() < prompt.len() {
+        echo.push('…');
+    }
+    format!(
+        "This is a **mock reply**, no model was involved. You said:\n\n> {}\n\n\
+         Things worth checking in the UI:\n\n\
+         1. Tokens arriving one at a time\n\
+         2. *Markdown* rendering\n\
+         3. Code blocks:\n\n\
+         ```rust\nfn main() {{\n    println!(\"Hello, world!\");\n}}\n```\n\n\
+         That's all for now.",
+        echo.replace('\n', " ")
+    )
+}
+
+/// `reply` as a model would stream it, a word at a time with the spacing kept
+fn mock_chunks(reply: String, prompt_tokens: usize) -> ChunkStream {
+    Box::pin(async_stream::stream! {
+        let started = Instant::now();
+        tokio::time::sleep(FIRST_TOKEN_DELAY).await;
+        let mut count = 0;
+        for token in reply.split_inclusive([' ', '\n']) {
+            yield Ok(Chunk::Content(token.to_string()));
+            count += 1;
+            tokio::time::sleep(TOKEN_DELAY).await;
+        }
+        let elapsed = started.elapsed();
+        yield Ok(Chunk::Done(Usage {
+            prompt_tokens: Some(prompt_tokens as u64),
+            completion_tokens: Some(count),
+            tokens_per_second: Some(count as f64 / elapsed.as_secs_f64()),
+            total_duration_ms: Some(elapsed.as_millis() as u64),
+            done_reason: Some("stop".to_string()),
+        }));
+    })
+}
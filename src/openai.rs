@@ -0,0 +1,222 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::{Json, State},
+    response::{sse::Event, AppendHeaders, IntoResponse, Response},
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    apply_system_prompt, chat_stream, check_prompt_length, sse_response, validate_request, AppError, AppState,
+    ChatOptions, ChatRequest, EventStream, JsonBody, KeepAlive, Message, StreamEvent, Usage,
+};
+
+/// Subset of OpenAI's chat completion request that maps onto Ollama
+#[derive(Debug, Deserialize)]
+pub struct CompletionRequest {
+    #[serde(default)]
+    model: String,
+    messages: Vec<Message>,
+    /// OpenAI defaults to a single JSON response
+    #[serde(default)]
+    stream: bool,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    seed: Option<u64>,
+    max_tokens: Option<u32>,
+    /// Not part of OpenAI's API; passed through to Ollama
+    keep_alive: Option<KeepAlive>,
+    /// Not part of OpenAI's API either; Ollama's context window option
+    num_ctx: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct Choice {
+    index: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delta: Option<Delta>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<Delta>,
+    finish_reason: Option<&'static str>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<Value>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Completion<'a> {
+    id: &'a str,
+    object: &'static str,
+    created: u64,
+    model: &'a str,
+    choices: Vec<Choice>,
+}
+
+/// `POST /v1/chat/completions`: the OpenAI chat API on top of `chat_stream`
+pub async fn completions_handler(
+    State(state): State<AppState>,
+    JsonBody(body): JsonBody<CompletionRequest>,
+) -> Response {
+    let options = ChatOptions {
+        temperature: body.temperature,
+        top_p: body.top_p,
+        seed: body.seed,
+        num_predict: body.max_tokens,
+        num_ctx: body.num_ctx,
+        ..Default::default()
+    };
+    let mut request = ChatRequest {
+        model: if body.model.is_empty() { state.config.model.clone() } else { body.model },
+        messages: body.messages,
+        stream: true,
+        system: None,
+        options: (!options.is_empty()).then_some(options),
+        keep_alive: body.keep_alive,
+        format: None,
+        provider: None,
+    };
+    if let Err(message) = validate_request(&state.config, &request) {
+        return error_response(&AppError::bad_request(message));
+    }
+    if let Err(error) = check_prompt_length(&state.config, &request.messages) {
+        return error_response(&error);
+    }
+    let permit = match state.generation_permit().await {
+        Ok(permit) => permit,
+        Err(error) => return error_response(&error),
+    };
+    apply_system_prompt(&state, &mut request);
+
+    let id = format!("chatcmpl-{:016x}", rand::random::<u64>());
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    let model = request.model.clone();
+    let events = chat_stream(&state, request, None, permit).await;
+
+    if body.stream {
+        stream_chunks(&state, events, id, created, model).await
+    } else {
+        collect_completion(events, id, created, model).await
+    }
+}
+
+/// Relay tokens as `chat.completion.chunk` deltas, ending with `data: [DONE]`
+async fn stream_chunks(state: &AppState, events: EventStream, id: String, created: u64, model: String) -> Response {
+    let mut first = true;
+    let mut called_tools = false;
+    let frames = events.flat_map(move |event| {
+        let chunk = |delta, finish_reason| {
+            let completion = Completion {
+                id: &id,
+                object: "chat.completion.chunk",
+                created,
+                model: &model,
+                choices: vec![Choice { index: 0, delta: Some(delta), message: None, finish_reason }],
+            };
+            Event::default().json_data(completion).expect("completion chunks always serialize")
+        };
+
+        let frames = match event {
+            StreamEvent::Token(token) => {
+                // Like OpenAI, only the first delta carries the role
+                let role = std::mem::take(&mut first).then_some("assistant");
+                vec![chunk(Delta { role, content: Some(token), ..Default::default() }, None)]
+            }
+            StreamEvent::ToolCalls(calls) => {
+                called_tools = true;
+                let role = std::mem::take(&mut first).then_some("assistant");
+                vec![chunk(Delta { role, tool_calls: Some(openai_tool_calls(&calls)), ..Default::default() }, None)]
+            }
+            StreamEvent::Done(usage) => {
+                let finish = if called_tools { "tool_calls" } else { finish_reason(&usage) };
+                vec![chunk(Delta::default(), Some(finish)), Event::default().data("[DONE]")]
+            }
+            StreamEvent::Error(error) => {
+                vec![Event::default().json_data(error_body(&error)).expect("errors always serialize")]
+            }
+        };
+        futures::stream::iter(frames.into_iter().map(Ok::<_, std::io::Error>))
+    });
+
+    sse_response(&state.config, frames)
+}
+
+/// Wait for the whole answer and return a single `chat.completion` object
+async fn collect_completion(mut events: EventStream, id: String, created: u64, model: String) -> Response {
+    let mut content = String::new();
+    let mut tool_calls = None;
+    let mut finish = "stop";
+    while let Some(event) = events.next().await {
+        match event {
+            StreamEvent::Token(token) => content.push_str(&token),
+            StreamEvent::ToolCalls(calls) => tool_calls = Some(openai_tool_calls(&calls)),
+            StreamEvent::Done(usage) => {
+                finish = if tool_calls.is_some() { "tool_calls" } else { finish_reason(&usage) };
+                break;
+            }
+            StreamEvent::Error(error) => return error_response(&error),
+        }
+    }
+
+    let completion = Completion {
+        id: &id,
+        object: "chat.completion",
+        created,
+        model: &model,
+        choices: vec![Choice {
+            index: 0,
+            delta: None,
+            message: Some(Delta { role: Some("assistant"), content: Some(content), tool_calls }),
+            finish_reason: Some(finish),
+        }],
+    };
+    Json(completion).into_response()
+}
+
+/// Reshape `[{"name", "arguments"}]` into OpenAI's `[{"id", "type": "function", "function": {...}}]`,
+/// where `arguments` is a JSON-encoded string
+fn openai_tool_calls(calls: &Value) -> Vec<Value> {
+    let calls = calls.as_array().map(Vec::as_slice).unwrap_or_default();
+    calls
+        .iter()
+        .enumerate()
+        .map(|(index, call)| {
+            let arguments = match call.get("arguments") {
+                Some(Value::String(raw)) => raw.clone(),
+                Some(other) => other.to_string(),
+                None => "{}".to_string(),
+            };
+            json!({
+                "index": index,
+                "id": format!("call_{:08x}", rand::random::<u32>()),
+                "type": "function",
+                "function": { "name": call.get("name").cloned().unwrap_or_default(), "arguments": arguments },
+            })
+        })
+        .collect()
+}
+
+/// Map Ollama's `done_reason` onto OpenAI's `finish_reason`
+fn finish_reason(usage: &Usage) -> &'static str {
+    match usage.done_reason.as_deref() {
+        Some("length") => "length",
+        _ => "stop",
+    }
+}
+
+/// OpenAI's error envelope
+fn error_body(error: &AppError) -> serde_json::Value {
+    json!({ "error": { "message": error.to_string(), "code": error.status().as_u16() } })
+}
+
+fn error_response(error: &AppError) -> Response {
+    (error.status(), AppendHeaders(error.headers()), Json(error_body(error))).into_response()
+}
@@ -0,0 +1,261 @@
+use std::{sync::Arc, time::Duration};
+
+use futures::{future::BoxFuture, StreamExt};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::{
+    backend::{ndjson_lines, ChatBackend, Chunk, ChunkStream, LineStream},
+    config::{Config, OpenAiUpstream},
+    AppError, ChatOptions, ChatRequest, GenerateRequest, KeepAlive, Message, OutputFormat, Usage,
+};
+
+/// A hosted OpenAI-compatible `/v1/chat/completions`, streamed as SSE and adapted to the
+/// same chunks as Ollama's stream, so cleaning, sessions and every handler work unchanged
+pub struct OpenAiBackend {
+    client: Client,
+    config: Arc<Config>,
+    upstream: OpenAiUpstream,
+}
+
+impl OpenAiBackend {
+    pub fn new(client: Client, config: Arc<Config>, upstream: OpenAiUpstream) -> Self {
+        Self { client, config, upstream }
+    }
+
+    /// POST `body` to the completions endpoint, turning anything but a successful response into an `AppError`
+    async fn start(&self, body: &CompletionBody<'_>) -> Result<reqwest::Response, AppError> {
+        let mut request = self
+            .client
+            .post(format!("{}/v1/chat/completions", self.upstream.url))
+            .timeout(self.config.request_timeout)
+            .json(body);
+        if let Some(key) = &self.upstream.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = request.send().await.map_err(|err| {
+            warn!(error = ?err, "Error calling the OpenAI provider");
+            AppError::from_reqwest(&err, self.config.request_timeout)
+        })?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        let status = response.status();
+        let detail = response.json::<ErrorBody>().await.ok().map(|body| body.error.message);
+        let error = AppError::UpstreamStatus { status, detail };
+        warn!(%error, "OpenAI provider rejected the request");
+        Err(error)
+    }
+
+    async fn completion_lines(&self, body: &CompletionBody<'_>) -> Result<LineStream, AppError> {
+        let response = self.start(body).await?;
+        Ok(ndjson_lines(response, (), self.config.request_timeout))
+    }
+}
+
+impl ChatBackend for OpenAiBackend {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn chat<'a>(&'a self, request: &'a ChatRequest) -> BoxFuture<'a, Result<ChunkStream, AppError>> {
+        Box::pin(async move { Ok(sse_chunks(self.completion_lines(&CompletionBody::chat(request)).await?)) })
+    }
+
+    fn generate<'a>(&'a self, request: &'a GenerateRequest) -> BoxFuture<'a, Result<ChunkStream, AppError>> {
+        Box::pin(async move {
+            // No bare completions here: the prompt becomes the only user turn
+            let messages = [Message { role: "user".to_string(), content: request.prompt.clone(), images: Vec::new() }];
+            let body = CompletionBody::new(&request.model, &messages, request.options.as_ref(), None);
+            Ok(sse_chunks(self.completion_lines(&body).await?))
+        })
+    }
+
+    /// The SSE lines as the API sent them
+    fn chat_raw<'a>(&'a self, request: &'a ChatRequest) -> BoxFuture<'a, Result<LineStream, AppError>> {
+        Box::pin(async move { self.completion_lines(&CompletionBody::chat(request)).await })
+    }
+
+    /// Hosted models are always loaded
+    fn warm_up<'a>(&'a self, _model: &'a str, _keep_alive: Option<KeepAlive>) -> BoxFuture<'a, Result<(), AppError>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// The request as OpenAI's API spells it
+#[derive(Debug, Serialize)]
+struct CompletionBody<'a> {
+    model: &'a str,
+    messages: Vec<Value>,
+    stream: bool,
+    /// Otherwise token counts are never reported when streaming
+    stream_options: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<&'a [String]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<Value>,
+}
+
+impl<'a> CompletionBody<'a> {
+    fn chat(request: &'a ChatRequest) -> Self {
+        Self::new(&request.model, &request.messages, request.options.as_ref(), request.format.as_ref())
+    }
+
+    /// `top_k` and `num_ctx` have no OpenAI equivalent and are dropped
+    fn new(
+        model: &'a str,
+        messages: &[Message],
+        options: Option<&'a ChatOptions>,
+        format: Option<&OutputFormat>,
+    ) -> Self {
+        Self {
+            model,
+            messages: messages.iter().map(openai_message).collect(),
+            stream: true,
+            stream_options: json!({ "include_usage": true }),
+            temperature: options.and_then(|options| options.temperature),
+            top_p: options.and_then(|options| options.top_p),
+            seed: options.and_then(|options| options.seed),
+            max_tokens: options.and_then(|options| options.num_predict),
+            stop: options.and_then(|options| options.stop.as_deref()),
+            response_format: format.map(|format| match format {
+                OutputFormat::Named(_) => json!({ "type": "json_object" }),
+                OutputFormat::Schema(schema) => {
+                    json!({ "type": "json_schema", "json_schema": { "name": "response", "schema": schema } })
+                }
+            }),
+        }
+    }
+}
+
+/// A message in OpenAI's shape: plain text, or text and `data:` image URLs as content parts
+fn openai_message(message: &Message) -> Value {
+    if message.images.is_empty() {
+        return json!({ "role": message.role, "content": message.content });
+    }
+    let text = json!({ "type": "text", "text": message.content });
+    let images = message.images.iter().map(|image| {
+        json!({ "type": "image_url", "image_url": { "url": format!("data:{};base64,{}", image_type(image), image) } })
+    });
+    json!({ "role": message.role, "content": std::iter::once(text).chain(images).collect::<Vec<_>>() })
+}
+
+/// Ollama takes bare base64; a data URL needs the type, told apart by the encoded magic bytes
+fn image_type(base64: &str) -> &'static str {
+    match base64 {
+        b if b.starts_with("iVBORw0KGgo") => "image/png",
+        b if b.starts_with("R0lGOD") => "image/gif",
+        b if b.starts_with("UklGR") => "image/webp",
+        _ => "image/jpeg",
+    }
+}
+
+/// `{"error": {"message": ...}}`, as OpenAI-compatible APIs report failures
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorDetail {
+    message: String,
+}
+
+/// One `data:` event of the completion stream
+#[derive(Debug, Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    /// Only on the final event, with `stream_options.include_usage`
+    usage: Option<StreamUsage>,
+    /// Some servers report failures mid-stream as an event of their own
+    error: Option<ErrorDetail>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+    #[serde(default)]
+    delta: StreamDelta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct StreamDelta {
+    content: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamUsage {
+    prompt_tokens: Option<u64>,
+    completion_tokens: Option<u64>,
+}
+
+/// Parse SSE lines into chunks. The stream ends at `data: [DONE]`, or with the body if a
+/// server leaves that out after the last choice has finished.
+fn sse_chunks(mut lines: LineStream) -> ChunkStream {
+    Box::pin(async_stream::stream! {
+        let started = std::time::Instant::now();
+        let mut usage = Usage::default();
+        let mut finished = false;
+        while let Some(line) = lines.next().await {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    yield Err(err);
+                    return;
+                }
+            };
+            // `event:`, `id:` and `:` comment lines carry nothing we need
+            let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+                continue;
+            };
+            if data == "[DONE]" {
+                yield Ok(Chunk::Done(finish_usage(usage, started.elapsed())));
+                return;
+            }
+            let Ok(chunk) = serde_json::from_str::<StreamChunk>(data) else {
+                continue;
+            };
+            if let Some(error) = chunk.error {
+                yield Err(AppError::ConnectionLost(error.message));
+                return;
+            }
+            for choice in chunk.choices {
+                if let Some(content) = choice.delta.content.filter(|content| !content.is_empty()) {
+                    yield Ok(Chunk::Content(content));
+                }
+                if let Some(reason) = choice.finish_reason {
+                    usage.done_reason = Some(reason);
+                    finished = true;
+                }
+            }
+            if let Some(counts) = chunk.usage {
+                usage.prompt_tokens = counts.prompt_tokens;
+                usage.completion_tokens = counts.completion_tokens;
+            }
+        }
+        if finished {
+            yield Ok(Chunk::Done(finish_usage(usage, started.elapsed())));
+        }
+    })
+}
+
+/// There are no server-side timings like Ollama's, so the rate is measured from here
+fn finish_usage(mut usage: Usage, elapsed: Duration) -> Usage {
+    usage.total_duration_ms = Some(elapsed.as_millis() as u64);
+    usage.tokens_per_second = usage
+        .completion_tokens
+        .filter(|_| elapsed > Duration::ZERO)
+        .map(|count| count as f64 / elapsed.as_secs_f64());
+    usage
+}
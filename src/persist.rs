@@ -0,0 +1,152 @@
+use std::{
+    path::Path,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{params, Connection};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::Message;
+
+/// Schema changes, applied in order; `PRAGMA user_version` records how many have run
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        conversation_id TEXT NOT NULL,
+        role TEXT NOT NULL,
+        content TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX messages_by_conversation ON messages (conversation_id, id);",
+];
+
+/// SQLite copy of every session's messages, so conversations survive a restart. Owned by its
+/// `DbWorker` thread once the server runs.
+#[derive(Debug)]
+pub struct ConversationDb {
+    conn: Connection,
+}
+
+impl ConversationDb {
+    /// Open (or create) the database at `path` and bring its schema up to date
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let mut conn = Connection::open(path)?;
+        migrate(&mut conn)?;
+        Ok(Self { conn })
+    }
+
+    /// A conversation's messages, oldest first; empty when it was never stored
+    pub fn load(&self, conversation_id: &str) -> rusqlite::Result<Vec<Message>> {
+        let mut statement =
+            self.conn.prepare_cached("SELECT role, content FROM messages WHERE conversation_id = ?1 ORDER BY id")?;
+        // Images aren't stored, so reloaded history is text only
+        let rows = statement.query_map([conversation_id], |row| {
+            Ok(Message { role: row.get(0)?, content: row.get(1)?, images: Vec::new() })
+        })?;
+        rows.collect()
+    }
+
+    pub fn append(&self, conversation_id: &str, message: &Message) -> rusqlite::Result<()> {
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or_default();
+        let mut statement = self.conn.prepare_cached(
+            "INSERT INTO messages (conversation_id, role, content, created_at) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        statement.execute(params![conversation_id, message.role, message.content, created_at])?;
+        Ok(())
+    }
+
+    /// Overwrite the conversation's most recent message, e.g. with a regenerated reply
+    pub fn replace_last(&self, conversation_id: &str, message: &Message) -> rusqlite::Result<()> {
+        let mut statement = self.conn.prepare_cached(
+            "UPDATE messages SET role = ?2, content = ?3
+            WHERE id = (SELECT MAX(id) FROM messages WHERE conversation_id = ?1)",
+        )?;
+        statement.execute(params![conversation_id, message.role, message.content])?;
+        Ok(())
+    }
+
+    /// Apply `op` for `DbWorker`, logging what fails
+    fn run(&self, op: Op) {
+        match op {
+            Op::Append(id, message) => {
+                if let Err(err) = self.append(&id, &message) {
+                    tracing::warn!(session = %id, error = %err, "Failed to persist session message");
+                }
+            }
+            Op::ReplaceLast(id, message) => {
+                if let Err(err) = self.replace_last(&id, &message) {
+                    tracing::warn!(session = %id, error = %err, "Failed to persist the active branch");
+                }
+            }
+            Op::Load(id, reply) => {
+                let messages = self.load(&id).unwrap_or_else(|err| {
+                    tracing::warn!(session = %id, error = %err, "Failed to load session history");
+                    Vec::new()
+                });
+                let _ = reply.send(messages);
+            }
+        }
+    }
+}
+
+/// One queued `ConversationDb` call
+enum Op {
+    Append(String, Message),
+    ReplaceLast(String, Message),
+    Load(String, oneshot::Sender<Vec<Message>>),
+}
+
+/// A `ConversationDb` on a thread of its own, so a slow disk never holds up the runtime or the
+/// session lock. Writes are queued and applied in order without waiting for them; a load waits
+/// its turn behind them, so it sees every write queued before it. Failures are logged, not returned.
+#[derive(Debug, Clone)]
+pub struct DbWorker {
+    ops: mpsc::UnboundedSender<Op>,
+}
+
+impl DbWorker {
+    /// Start the thread; it stops once every `DbWorker` for it is dropped
+    pub fn spawn(db: ConversationDb) -> Self {
+        let (ops, mut queue) = mpsc::unbounded_channel();
+        thread::Builder::new()
+            .name("conversation-db".to_string())
+            .spawn(move || {
+                while let Some(op) = queue.blocking_recv() {
+                    db.run(op);
+                }
+            })
+            .expect("failed to start the session database thread");
+        Self { ops }
+    }
+
+    pub fn append(&self, conversation_id: &str, message: Message) {
+        let _ = self.ops.send(Op::Append(conversation_id.to_string(), message));
+    }
+
+    pub fn replace_last(&self, conversation_id: &str, message: Message) {
+        let _ = self.ops.send(Op::ReplaceLast(conversation_id.to_string(), message));
+    }
+
+    /// The stored history, as `ConversationDb::load`; empty when it can't be read
+    pub async fn load(&self, conversation_id: &str) -> Vec<Message> {
+        let (reply, loaded) = oneshot::channel();
+        if self.ops.send(Op::Load(conversation_id.to_string(), reply)).is_err() {
+            return Vec::new();
+        }
+        loaded.await.unwrap_or_default()
+    }
+}
+
+/// Run whichever `MIGRATIONS` this database hasn't seen yet, each in its own transaction
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let applied: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", version as i64 + 1)?;
+        tx.commit()?;
+        tracing::info!("🗄️ Applied conversation schema migration {}", version + 1);
+    }
+    Ok(())
+}
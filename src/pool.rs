@@ -0,0 +1,54 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use tokio::sync::{mpsc, Mutex};
+use tracing::warn;
+
+use crate::AppError;
+
+/// One generation waiting for a worker: the whole backend call and relay, sending its events
+/// down the channel it was given when queued
+pub type Job = BoxFuture<'static, ()>;
+
+/// A fixed set of worker tasks (`--workers`) taking generations off a bounded queue (`--queue-depth`).
+/// However many clients arrive, at most that many generations hit the backend at once, and
+/// a burst beyond what the queue holds is turned away instead of piling up.
+#[derive(Debug, Clone)]
+pub struct WorkerPool {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl WorkerPool {
+    /// Start `workers` tasks sharing a queue of `depth` jobs
+    pub fn new(workers: usize, depth: usize) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>(depth.max(1));
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..workers {
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                loop {
+                    // Only held while waiting, so the other workers take the next jobs meanwhile
+                    let job = queue.lock().await.recv().await;
+                    match job {
+                        Some(job) => job.await,
+                        None => return,
+                    }
+                }
+            });
+        }
+        Self { jobs }
+    }
+
+    /// Queue `job` for the next free worker; 503 when the queue is already full
+    pub fn submit(&self, job: Job) -> Result<(), AppError> {
+        self.jobs.try_send(job).map_err(|_| {
+            warn!(depth = self.jobs.max_capacity(), "Worker queue full, rejecting request");
+            AppError::Overloaded
+        })
+    }
+
+    /// Jobs queued and not yet picked up by a worker
+    pub fn queued(&self) -> usize {
+        self.jobs.max_capacity() - self.jobs.capacity()
+    }
+}
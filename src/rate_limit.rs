@@ -0,0 +1,212 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    auth::{bearer_token, is_known_key},
+    parse_query, AppError, AppState,
+};
+
+/// Requests seen from one key in the current window
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+/// Fixed-window request counter, keyed by client IP address unless told otherwise
+#[derive(Debug)]
+pub struct RateLimiter<K = IpAddr> {
+    limit: u32,
+    window: Duration,
+    windows: Mutex<HashMap<K, Window>>,
+}
+
+impl<K: Eq + Hash + Send + 'static> RateLimiter<K> {
+    /// Allow `limit` requests per `window`; a limit of 0 disables limiting
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self { limit, window, windows: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.limit > 0
+    }
+
+    /// Count a request for `key`, or say how long until it may retry
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        self.check_all(vec![key]).map_err(|(_, retry_after)| retry_after)
+    }
+
+    /// Count a request against every key, or against none of them if one is out of budget, saying
+    /// which one and how long until it may retry
+    pub fn check_all(&self, mut keys: Vec<K>) -> Result<(), (K, Duration)> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        let mut windows = self.windows.lock().unwrap();
+        let now = Instant::now();
+        let exceeded = keys.iter().enumerate().find_map(|(index, key)| {
+            windows.get(key).and_then(|window| self.retry_after(window, now)).map(|retry_after| (index, retry_after))
+        });
+        if let Some((index, retry_after)) = exceeded {
+            return Err((keys.swap_remove(index), retry_after));
+        }
+        for key in keys {
+            let entry = windows.entry(key).or_insert(Window { started: now, count: 0 });
+            if now.duration_since(entry.started) >= self.window {
+                *entry = Window { started: now, count: 0 };
+            }
+            entry.count += 1;
+        }
+        Ok(())
+    }
+
+    /// How long until `key` may retry, without counting anything; `None` if it may go now
+    pub fn peek(&self, key: &K) -> Option<Duration> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let windows = self.windows.lock().unwrap();
+        windows.get(key).and_then(|window| self.retry_after(window, Instant::now()))
+    }
+
+    fn retry_after(&self, window: &Window, now: Instant) -> Option<Duration> {
+        let elapsed = now.duration_since(window.started);
+        (elapsed < self.window && window.count >= self.limit).then(|| self.window - elapsed)
+    }
+
+    /// Forget keys whose window has expired
+    pub fn prune(&self) {
+        let mut windows = self.windows.lock().unwrap();
+        windows.retain(|_, w| w.started.elapsed() < self.window);
+    }
+
+    /// Periodically prune stale windows so idle clients don't accumulate forever
+    pub fn spawn_pruner(self: &Arc<Self>) {
+        if !self.is_enabled() {
+            return;
+        }
+        let limiter = Arc::downgrade(self);
+        let period = self.window.max(Duration::from_secs(1));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let Some(limiter) = limiter.upgrade() else { break };
+                limiter.prune();
+            }
+        });
+    }
+}
+
+/// Who a request counts against besides its IP: the API key it authenticates with, and the
+/// conversation it names. Kept apart, so a session id can't spend someone's key budget.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClientKey {
+    Session(String),
+    ApiKey(String),
+}
+
+impl ClientKey {
+    /// The bearer key, then the `session_id` query parameter, whichever the request has. Both
+    /// count, so a fresh `session_id` per request doesn't get around the key's budget. Sessions
+    /// named in a JSON body (`/title`, `/regenerate`) only count against the key, since the body
+    /// isn't read here.
+    ///
+    /// Only a configured key is worth keying on: with auth off any string passes for one, and a
+    /// request with a wrong key is turned away by `require_api_key` anyway, so it shouldn't cost
+    /// the session it names either.
+    fn all(request: &Request, api_keys: &[String]) -> Vec<Self> {
+        let key = Self::api_key(request.headers(), api_keys);
+        if key.is_none() && !api_keys.is_empty() {
+            return Vec::new();
+        }
+        let session = parse_query(request.uri().query().unwrap_or_default())
+            .ok()
+            .and_then(|mut params| params.remove("session_id"))
+            .filter(|id| !id.is_empty())
+            .map(Self::Session);
+        key.into_iter().chain(session).collect()
+    }
+
+    /// The configured key `headers` authenticate with, if any
+    pub fn api_key(headers: &HeaderMap, api_keys: &[String]) -> Option<Self> {
+        let token = bearer_token(headers).filter(|_| !api_keys.is_empty())?;
+        is_known_key(api_keys, token).then(|| Self::ApiKey(token.to_string()))
+    }
+
+    /// What a 429 for this key says was exceeded
+    fn scope(&self) -> &'static str {
+        match self {
+            Self::Session(_) => "session",
+            Self::ApiKey(_) => "API key",
+        }
+    }
+}
+
+/// Middleware for the chat routes: 429 once a client exceeds its per-IP budget, or the API key
+/// or session it sends exceeds its own (`--session-rate-limit`). A request has to pass all of them.
+pub async fn limit_requests(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ip = client_ip(request.headers(), peer, state.config.trust_forwarded_for);
+    let keys = ClientKey::all(&request, &state.config.api_keys);
+    if let Err(err) = check_limits(&state, ip, keys) {
+        return err.into_response();
+    }
+    next.run(request).await
+}
+
+/// Count a request against its IP and `keys`, or against none of them if any is out of budget
+pub fn check_limits(state: &AppState, ip: IpAddr, keys: Vec<ClientKey>) -> Result<(), AppError> {
+    if let Some(retry_after) = state.ip_limiter.peek(&ip) {
+        tracing::warn!(%ip, "Rate limit exceeded");
+        return Err(rate_limited(retry_after, "IP address"));
+    }
+    if let Err((key, retry_after)) = state.session_limiter.check_all(keys) {
+        let scope = key.scope();
+        tracing::warn!(%ip, scope, "Rate limit exceeded");
+        return Err(rate_limited(retry_after, scope));
+    }
+    // Only fails when a request from the same IP took the last slot since the peek
+    state.ip_limiter.check(ip).map_err(|retry_after| {
+        tracing::warn!(%ip, "Rate limit exceeded");
+        rate_limited(retry_after, "IP address")
+    })
+}
+
+/// The first `X-Forwarded-For` hop when the proxy is trusted, else the TCP peer
+pub fn client_ip(headers: &HeaderMap, peer: SocketAddr, trust_forwarded_for: bool) -> IpAddr {
+    if trust_forwarded_for {
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .and_then(|first| first.trim().parse().ok());
+        if let Some(ip) = forwarded {
+            return ip;
+        }
+    }
+    peer.ip()
+}
+
+fn rate_limited(retry_after: Duration, scope: &'static str) -> AppError {
+    // Round up so clients never retry a moment too early
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    AppError::RateLimited { retry_after: secs, scope }
+}
@@ -0,0 +1,87 @@
+use axum::extract::{Json, State};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    apply_system_prompt, chat_stream, collect_reply, session::Branches, validate_request, AppError, AppState,
+    ChatOptions, ChatRequest, JsonBody, MAX_SESSION_ID_LEN,
+};
+
+/// Body of `POST /regenerate`
+#[derive(Debug, Deserialize)]
+pub struct RegenerateRequest {
+    session_id: String,
+    /// Empty means "use the configured default model"
+    #[serde(default)]
+    model: String,
+    system: Option<String>,
+    /// E.g. another `seed` or `temperature`, so the new reply comes out different
+    options: Option<ChatOptions>,
+    /// Switch back to an existing reply instead of generating one
+    branch: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Regenerated {
+    /// The reply the conversation now continues from
+    reply: String,
+    #[serde(flatten)]
+    branches: Branches,
+}
+
+/// `POST /regenerate`: answer the session's last user message again. The reply it had is kept
+/// as a branch, so `{"branch": n}` can switch back to it later; either way the response lists
+/// every branch and says which is active.
+pub async fn regenerate_handler(
+    State(state): State<AppState>,
+    JsonBody(request): JsonBody<RegenerateRequest>,
+) -> Result<Json<Regenerated>, AppError> {
+    let id = &request.session_id;
+    if id.is_empty() || id.len() > MAX_SESSION_ID_LEN {
+        return Err(format!("session_id must be 1-{} characters", MAX_SESSION_ID_LEN).into());
+    }
+    let mut messages = state.sessions.history(id).await;
+    if messages.is_empty() {
+        return Err(AppError::NotFound(format!("unknown session: {:?}", id)));
+    }
+    if messages.last().is_none_or(|message| message.role != "assistant") {
+        return Err(AppError::bad_request("the session doesn't end with a reply to regenerate"));
+    }
+
+    let branches = match request.branch {
+        Some(index) => state
+            .sessions
+            .select_branch(id, index)
+            .await
+            .ok_or_else(|| AppError::bad_request(format!("no branch {} in this session", index)))?,
+        None => {
+            let len = messages.len();
+            messages.pop();
+            let model = if request.model.is_empty() { state.config.model.clone() } else { request.model };
+            let mut chat = ChatRequest {
+                model,
+                messages,
+                stream: true,
+                system: request.system,
+                options: request.options,
+                keep_alive: None,
+                format: None,
+                provider: None,
+            };
+            validate_request(&state.config, &chat)?;
+            let permit = state.generation_permit().await?;
+            apply_system_prompt(&state, &mut chat);
+            let (reply, _) = collect_reply(chat_stream(&state, chat, None, permit).await).await?;
+            // Another turn may have finished meanwhile, leaving nothing to replace
+            let branches = state
+                .sessions
+                .add_branch(id, len, reply)
+                .await
+                .ok_or_else(|| AppError::bad_request("the session moved on while regenerating"))?;
+            info!(session = %id, branch = branches.active_branch, "🔁 Regenerated reply");
+            branches
+        }
+    };
+    let reply = branches.branches[branches.active_branch].clone();
+    Ok(Json(Regenerated { reply, branches }))
+}
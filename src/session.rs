@@ -0,0 +1,280 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+use serde::Serialize;
+
+use crate::{
+    persist::{ConversationDb, DbWorker},
+    Message,
+};
+
+/// Conversation history for one `session_id`
+#[derive(Debug)]
+struct Session {
+    messages: Vec<Message>,
+    last_seen: Instant,
+    /// Generated by `POST /title`, kept so it's only generated once (in memory only)
+    title: Option<String>,
+    /// Every reply `POST /regenerate` has produced for the last user message, the one in
+    /// `messages` among them; empty until the first regeneration (in memory only)
+    replies: Vec<String>,
+    /// Which of `replies` is the last message
+    active: usize,
+}
+
+impl Session {
+    fn new(messages: Vec<Message>) -> Self {
+        Self { messages, last_seen: Instant::now(), title: None, replies: Vec::new(), active: 0 }
+    }
+
+    /// The replies to the last user message; `None` when the conversation doesn't end in one
+    fn branches(&mut self) -> Option<Branches> {
+        let last = self.messages.last().filter(|message| message.role == "assistant")?;
+        if self.replies.is_empty() {
+            self.replies.push(last.content.clone());
+            self.active = 0;
+        }
+        Some(Branches { active_branch: self.active, branches: self.replies.clone() })
+    }
+
+    /// Make branch `index` the last message
+    fn activate(&mut self, index: usize) {
+        self.active = index;
+        if let Some(last) = self.messages.last_mut() {
+            last.content = self.replies[index].clone();
+        }
+    }
+}
+
+/// The alternate replies to a session's last user message, and which one the history continues from
+#[derive(Debug, Clone, Serialize)]
+pub struct Branches {
+    pub active_branch: usize,
+    pub branches: Vec<String>,
+}
+
+/// In-memory conversation store keyed by `session_id`, with idle-based eviction. Past its
+/// capacity, the least recently used session is dropped to make room for a new one.
+/// With a `ConversationDb`, every message is also written through to SQLite, and a session
+/// that isn't in memory (evicted, or from before a restart) is reloaded from there. The database
+/// is only ever touched by its `DbWorker` thread, never while the sessions are locked.
+#[derive(Debug)]
+pub struct SessionStore {
+    sessions: Mutex<LruCache<String, Session>>,
+    ttl: Duration,
+    db: Option<DbWorker>,
+}
+
+impl SessionStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self { sessions: Mutex::new(LruCache::unbounded()), ttl, db: None }
+    }
+
+    /// Keep at most `capacity` sessions in memory; `None` leaves the store unbounded
+    pub fn with_capacity(self, capacity: Option<NonZeroUsize>) -> Self {
+        if let Some(capacity) = capacity {
+            self.sessions.lock().unwrap().resize(capacity);
+        }
+        self
+    }
+
+    /// Persist conversations to `db`
+    pub fn with_db(mut self, db: ConversationDb) -> Self {
+        self.db = Some(DbWorker::spawn(db));
+        self
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Append a finished turn, `prompt` and the `reply` to it, creating the session if needed
+    pub async fn record_turn(&self, id: &str, prompt: Message, reply: Message) {
+        self.with_session(id, true, |session, db| {
+            for message in [prompt, reply] {
+                if let Some(db) = db {
+                    db.append(id, message.clone());
+                }
+                session.messages.push(message);
+            }
+            // A new turn leaves the previous one's alternates behind
+            session.replies.clear();
+        })
+        .await;
+    }
+
+    /// The session's history without adding to it; empty when there is no such session
+    pub async fn history(&self, id: &str) -> Vec<Message> {
+        self.with_session(id, false, |session, _| session.messages.clone()).await.unwrap_or_default()
+    }
+
+    /// Run `f` on the session under the lock, first reading it back from the database if it isn't in memory.
+    /// The read happens with the lock released. `None` when there's no such session and `create` is off.
+    async fn with_session<T>(
+        &self,
+        id: &str,
+        create: bool,
+        f: impl FnOnce(&mut Session, Option<&DbWorker>) -> T,
+    ) -> Option<T> {
+        let mut loaded = None;
+        loop {
+            {
+                let mut sessions = self.sessions.lock().unwrap();
+                // Whoever loaded it meanwhile wins, having possibly added to it already
+                if sessions.contains(id) || loaded.is_some() || self.db.is_none() {
+                    let messages: Vec<Message> = loaded.take().unwrap_or_default();
+                    if !sessions.contains(id) {
+                        if messages.is_empty() && !create {
+                            return None;
+                        }
+                        if sessions.len() == sessions.cap().get() {
+                            tracing::debug!(
+                                capacity = sessions.cap().get(),
+                                "Session store full, evicting the least recently used"
+                            );
+                        }
+                    }
+                    // Looking the session up also marks it as the most recently used, so reads count as
+                    // activity too and recency order stays `last_seen` order
+                    let session = sessions.get_or_insert_mut(id.to_string(), || Session::new(messages));
+                    session.last_seen = Instant::now();
+                    return Some(f(session, self.db.as_ref()));
+                }
+            }
+            loaded = Some(self.load(id).await);
+        }
+    }
+
+    pub fn title(&self, id: &str) -> Option<String> {
+        self.sessions.lock().unwrap().peek(id).and_then(|session| session.title.clone())
+    }
+
+    /// Remember `title` for the session, if it's still around
+    pub fn set_title(&self, id: &str, title: String) {
+        if let Some(session) = self.sessions.lock().unwrap().peek_mut(id) {
+            session.title = Some(title);
+        }
+    }
+
+    /// Replace the session's last reply with `reply`, keeping the one it replaces as a branch.
+    /// `None` when the session doesn't end with a reply, or no longer has `len` messages because
+    /// another turn finished meanwhile, leaving `reply` answering a question that isn't last.
+    pub async fn add_branch(&self, id: &str, len: usize, reply: String) -> Option<Branches> {
+        self.update_branches(id, |session| {
+            if session.messages.len() != len {
+                return None;
+            }
+            session.branches()?;
+            session.replies.push(reply);
+            session.activate(session.replies.len() - 1);
+            Some(())
+        })
+        .await
+    }
+
+    /// Continue the session from its alternate reply `index` instead; `None` when there is no such branch
+    pub async fn select_branch(&self, id: &str, index: usize) -> Option<Branches> {
+        self.update_branches(id, |session| {
+            if index >= session.branches()?.branches.len() {
+                return None;
+            }
+            session.activate(index);
+            Some(())
+        })
+        .await
+    }
+
+    /// Apply `change` to the session's branches, writing the reply it leaves active through to the database
+    async fn update_branches(&self, id: &str, change: impl FnOnce(&mut Session) -> Option<()>) -> Option<Branches> {
+        self.with_session(id, false, |session, db| {
+            change(session)?;
+            if let (Some(db), Some(last)) = (db, session.messages.last()) {
+                db.replace_last(id, last.clone());
+            }
+            session.branches()
+        })
+        .await
+        .flatten()
+    }
+
+    /// Stored history for a session that isn't in memory; empty without a database
+    async fn load(&self, id: &str) -> Vec<Message> {
+        match &self.db {
+            Some(db) => db.load(id).await,
+            None => Vec::new(),
+        }
+    }
+
+    /// Drop every session that hasn't been touched within the TTL, returning how many went
+    pub fn evict_idle(&self) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        // Recency order is `last_seen` order, so the idle sessions are all at the LRU end
+        let mut evicted = 0;
+        while sessions.peek_lru().is_some_and(|(_, session)| session.last_seen.elapsed() >= self.ttl) {
+            sessions.pop_lru();
+            evicted += 1;
+        }
+        evicted
+    }
+
+    /// Periodically evict idle sessions for as long as the store is alive
+    pub fn spawn_sweeper(self: &Arc<Self>) {
+        let store = Arc::downgrade(self);
+        let period = self.ttl.min(Duration::from_secs(60));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let Some(store) = store.upgrade() else { break };
+                let evicted = store.evict_idle();
+                if evicted > 0 {
+                    tracing::info!("🧹 Evicted {} idle session(s)", evicted);
+                }
+            }
+        });
+    }
+}
+
+/// A session the streamed assistant reply should be recorded into once it completes. The prompt
+/// waits here until then, so a turn that fails or is abandoned leaves the history as it was.
+#[derive(Debug, Clone)]
+pub struct SessionHandle {
+    pub store: Arc<SessionStore>,
+    pub id: String,
+    pub prompt: Message,
+}
+
+impl SessionHandle {
+    pub async fn record_reply(self, content: String) {
+        let reply = Message { role: "assistant".to_string(), content, images: Vec::new() };
+        self.store.record_turn(&self.id, self.prompt, reply).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str) -> Message {
+        Message { role: role.to_string(), content: role.to_string(), images: Vec::new() }
+    }
+
+    #[tokio::test]
+    async fn reading_a_session_keeps_it_alive() {
+        let store = SessionStore::new(Duration::from_millis(200));
+        store.record_turn("read", message("user"), message("assistant")).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        store.record_turn("unread", message("user"), message("assistant")).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(store.history("read").await.len(), 2);
+        tokio::time::sleep(Duration::from_millis(140)).await;
+
+        assert_eq!(store.evict_idle(), 1);
+        assert_eq!(store.history("read").await.len(), 2);
+        assert!(store.history("unread").await.is_empty());
+    }
+}
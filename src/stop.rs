@@ -0,0 +1,80 @@
+use crate::{StreamEvent, Usage};
+
+/// Ends the stream at the first stop sequence in the output, even if the backend ignored `options.stop`.
+///
+/// Text that could be the start of a stop sequence split across tokens is held back until the
+/// next token settles it, so nothing past the stop point ever reaches the client.
+#[derive(Debug)]
+pub struct StopSequences {
+    stops: Vec<String>,
+    held: String,
+}
+
+impl StopSequences {
+    /// `None` when there is nothing to watch for
+    pub fn new(stops: &[String]) -> Option<Self> {
+        let stops: Vec<String> = stops.iter().filter(|stop| !stop.is_empty()).cloned().collect();
+        (!stops.is_empty()).then_some(Self { stops, held: String::new() })
+    }
+
+    /// Run `events` through the matcher. Once a stop sequence shows up, the output ends with the
+    /// text before it and a `Done` whose `done_reason` is `stop`; later events are dropped.
+    pub fn process(&mut self, events: Vec<StreamEvent>) -> Vec<StreamEvent> {
+        let mut out = Vec::with_capacity(events.len());
+        for event in events {
+            match event {
+                StreamEvent::Token(token) => {
+                    if self.push(&token, &mut out) {
+                        let usage = Usage { done_reason: Some("stop".to_string()), ..Default::default() };
+                        out.push(StreamEvent::Done(usage));
+                        break;
+                    }
+                }
+                other => {
+                    out.extend(self.flush());
+                    out.push(other);
+                }
+            }
+        }
+        out
+    }
+
+    /// Whatever is still held back, for a stream that ends here
+    pub fn flush(&mut self) -> Vec<StreamEvent> {
+        emit_text(std::mem::take(&mut self.held))
+    }
+
+    /// Add `token` to the output; `true` once a stop sequence was hit
+    fn push(&mut self, token: &str, out: &mut Vec<StreamEvent>) -> bool {
+        self.held.push_str(token);
+        if let Some(at) = self.stops.iter().filter_map(|stop| self.held.find(stop.as_str())).min() {
+            self.held.truncate(at);
+            out.extend(self.flush());
+            return true;
+        }
+
+        let keep = self.stops.iter().map(|stop| partial_match(&self.held, stop)).max().unwrap_or(0);
+        let text: String = self.held.drain(..self.held.len() - keep).collect();
+        out.extend(emit_text(text));
+        false
+    }
+}
+
+/// Length of the longest tail of `text` that is a proper prefix of `stop`
+fn partial_match(text: &str, stop: &str) -> usize {
+    stop.char_indices()
+        .skip(1)
+        .map(|(end, _)| &stop[..end])
+        .filter(|prefix| text.ends_with(prefix))
+        .map(str::len)
+        .max()
+        .unwrap_or(0)
+}
+
+fn emit_text(text: String) -> Vec<StreamEvent> {
+    if text.is_empty() {
+        Vec::new()
+    } else {
+        vec![StreamEvent::Token(text)]
+    }
+}
@@ -0,0 +1,23 @@
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+
+/// `{{name}}`, optionally with spaces inside the braces
+static PLACEHOLDER_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{\{\s*([A-Za-z0-9_]+)\s*\}\}").unwrap());
+
+/// Fill every `{{placeholder}}` in `template` from `vars`. Values are inserted as they are,
+/// so a value containing braces is never expanded again.
+pub fn render(name: &str, template: &str, vars: &HashMap<String, String>) -> Result<String, String> {
+    let mut missing: Vec<&str> = Vec::new();
+    for captures in PLACEHOLDER_REGEX.captures_iter(template) {
+        let placeholder = captures.get(1).unwrap().as_str();
+        if !vars.contains_key(placeholder) && !missing.contains(&placeholder) {
+            missing.push(placeholder);
+        }
+    }
+    if !missing.is_empty() {
+        return Err(format!("template {:?} needs parameter(s): {}", name, missing.join(", ")));
+    }
+    Ok(PLACEHOLDER_REGEX.replace_all(template, |captures: &Captures| vars[&captures[1]].clone()).into_owned())
+}
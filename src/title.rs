@@ -0,0 +1,127 @@
+use axum::extract::{Json, State};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    chat_stream, check_prompt_length, collect_reply, validate_model, validate_request, AppError, AppState, ChatOptions,
+    ChatRequest, JsonBody, Message, MAX_SESSION_ID_LEN,
+};
+
+const TITLE_INSTRUCTIONS: &str = "You name conversations. Reply with a title of 3 to 6 words for the \
+    conversation below, and nothing else: no quotes, no explanation.";
+
+/// Plenty for six words; stops a chatty model from rambling on
+const TITLE_MAX_TOKENS: u32 = 24;
+/// How much of each side of the first exchange the model reads; a title needs no more
+const EXCHANGE_MAX_CHARS: usize = 2000;
+
+/// Body of `POST /title`: a session to name, or the messages themselves
+#[derive(Debug, Deserialize)]
+pub struct TitleRequest {
+    session_id: Option<String>,
+    #[serde(default)]
+    messages: Vec<Message>,
+    /// Empty means "use the configured default model"
+    #[serde(default)]
+    model: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Title {
+    title: String,
+    /// Whether this is the session's title from an earlier call
+    cached: bool,
+}
+
+/// `POST /title`: a short title for a conversation, from its first user message and the reply to it
+pub async fn title_handler(
+    State(state): State<AppState>,
+    JsonBody(request): JsonBody<TitleRequest>,
+) -> Result<Json<Title>, AppError> {
+    let model = if request.model.is_empty() { state.config.model.clone() } else { request.model };
+    validate_model(&state.config, &model)?;
+
+    let messages = match &request.session_id {
+        Some(id) if id.is_empty() || id.len() > MAX_SESSION_ID_LEN => {
+            return Err(format!("session_id must be 1-{} characters", MAX_SESSION_ID_LEN).into());
+        }
+        Some(id) => {
+            if let Some(title) = state.sessions.title(id) {
+                return Ok(Json(Title { title, cached: true }));
+            }
+            let history = state.sessions.history(id).await;
+            if history.is_empty() {
+                return Err(AppError::NotFound(format!("unknown session: {:?}", id)));
+            }
+            history
+        }
+        // Checked like a `/chat` body, since they're just as much the client's
+        None => {
+            let body = ChatRequest {
+                model: model.clone(),
+                messages: request.messages,
+                stream: true,
+                system: None,
+                options: None,
+                keep_alive: None,
+                format: None,
+                provider: None,
+            };
+            validate_request(&state.config, &body)?;
+            check_prompt_length(&state.config, &body.messages)?;
+            body.messages
+        }
+    };
+    let exchange = first_exchange(&messages).ok_or_else(|| AppError::bad_request("no user message to title"))?;
+
+    let chat = ChatRequest {
+        model,
+        messages: vec![
+            Message { role: "system".to_string(), content: TITLE_INSTRUCTIONS.to_string(), images: Vec::new() },
+            Message { role: "user".to_string(), content: exchange, images: Vec::new() },
+        ],
+        stream: true,
+        system: None,
+        options: Some(ChatOptions { temperature: Some(0.2), num_predict: Some(TITLE_MAX_TOKENS), ..Default::default() }),
+        keep_alive: None,
+        format: None,
+        provider: None,
+    };
+    let permit = state.generation_permit().await?;
+    let (reply, _) = collect_reply(chat_stream(&state, chat, None, permit).await).await?;
+    let title = tidy_title(&reply);
+    if title.is_empty() {
+        return Err(AppError::Parse("the model answered with an empty title".to_string()));
+    }
+
+    if let Some(id) = &request.session_id {
+        info!(session = %id, %title, "🏷️ Titled session");
+        state.sessions.set_title(id, title.clone());
+    }
+    Ok(Json(Title { title, cached: false }))
+}
+
+/// The first user message and the assistant's answer to it, if there is one yet, as a transcript.
+/// Each is cut to `EXCHANGE_MAX_CHARS`, so a long session's opening doesn't cost a long prompt.
+fn first_exchange(messages: &[Message]) -> Option<String> {
+    let opening = |message: &Message| -> String { message.content.chars().take(EXCHANGE_MAX_CHARS).collect() };
+    let user = messages.iter().position(|message| message.role == "user")?;
+    let mut exchange = format!("User: {}", opening(&messages[user]));
+    if let Some(reply) = messages[user + 1..].iter().find(|message| message.role == "assistant") {
+        exchange.push_str(&format!("\nAssistant: {}", opening(reply)));
+    }
+    Some(exchange)
+}
+
+/// First line of the answer, without the quotes or final punctuation models like to add
+fn tidy_title(reply: &str) -> String {
+    const QUOTES: &[char] = &['"', '\'', '`', '“', '”', '‘', '’', '*'];
+    let line = reply.trim().lines().next().unwrap_or_default();
+    let line = line.strip_prefix("Title:").unwrap_or(line);
+    line.trim()
+        .trim_matches(QUOTES)
+        .trim_end_matches(['.', '!', '?', ',', ';', ':'])
+        .trim_matches(QUOTES)
+        .trim()
+        .to_string()
+}
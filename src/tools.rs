@@ -0,0 +1,96 @@
+use serde_json::Value;
+
+use crate::StreamEvent;
+
+const TOOL_CALLS_MARKER: &str = "[TOOL_CALLS]";
+
+/// Pulls `[TOOL_CALLS] [...]` payloads out of the token stream (with `--forward-tool-calls`).
+///
+/// Text before the marker is forwarded as usual; the JSON after it is buffered until it parses,
+/// then sent as one `StreamEvent::ToolCalls`. A payload that never parses is forwarded as text.
+#[derive(Debug, Default)]
+pub struct ToolCallParser {
+    /// Text held back because it might be the start of a marker split across tokens
+    held: String,
+    /// Everything after the marker, while a payload is being collected
+    payload: Option<String>,
+}
+
+impl ToolCallParser {
+    /// Run `events` through the parser, replacing tool-call text with `ToolCalls` events
+    pub fn process(&mut self, events: Vec<StreamEvent>) -> Vec<StreamEvent> {
+        let mut out = Vec::with_capacity(events.len());
+        for event in events {
+            match event {
+                StreamEvent::Token(token) => self.push(&token, &mut out),
+                StreamEvent::Done(usage) => {
+                    self.finish(&mut out);
+                    out.push(StreamEvent::Done(usage));
+                }
+                other => out.push(other),
+            }
+        }
+        out
+    }
+
+    fn push(&mut self, token: &str, out: &mut Vec<StreamEvent>) {
+        if let Some(payload) = &mut self.payload {
+            payload.push_str(token);
+            if let Some(calls) = parse_payload(payload) {
+                self.payload = None;
+                out.push(StreamEvent::ToolCalls(calls));
+            }
+            return;
+        }
+
+        self.held.push_str(token);
+        if let Some(at) = self.held.find(TOOL_CALLS_MARKER) {
+            let rest = self.held.split_off(at);
+            emit_text(std::mem::take(&mut self.held), out);
+            self.payload = Some(String::new());
+            self.push(&rest[TOOL_CALLS_MARKER.len()..], out);
+            return;
+        }
+
+        // Forward everything except a trailing partial marker such as `[TOOL_`
+        let keep = (1..TOOL_CALLS_MARKER.len())
+            .rev()
+            .find(|&len| self.held.ends_with(&TOOL_CALLS_MARKER[..len]))
+            .unwrap_or(0);
+        let text: String = self.held.drain(..self.held.len() - keep).collect();
+        emit_text(text, out);
+    }
+
+    /// Whatever is still buffered, for a stream that ended without `done`
+    pub fn flush(&mut self) -> Vec<StreamEvent> {
+        let mut out = Vec::new();
+        self.finish(&mut out);
+        out
+    }
+
+    /// The stream ended: flush whatever is still buffered
+    fn finish(&mut self, out: &mut Vec<StreamEvent>) {
+        emit_text(std::mem::take(&mut self.held), out);
+        if let Some(payload) = self.payload.take() {
+            match parse_payload(&payload) {
+                Some(calls) => out.push(StreamEvent::ToolCalls(calls)),
+                None => emit_text(format!("{}{}", TOOL_CALLS_MARKER, payload), out),
+            }
+        }
+    }
+}
+
+/// A payload is complete once it parses as a JSON array (or a single call object)
+fn parse_payload(payload: &str) -> Option<Value> {
+    match serde_json::from_str(payload.trim()).ok()? {
+        Value::Array(calls) => Some(Value::Array(calls)),
+        call @ Value::Object(_) => Some(Value::Array(vec![call])),
+        _ => None,
+    }
+}
+
+fn emit_text(text: String, out: &mut Vec<StreamEvent>) {
+    if !text.is_empty() {
+        out.push(StreamEvent::Token(text));
+    }
+}
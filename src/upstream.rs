@@ -0,0 +1,107 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::config::{BalanceStrategy, Config};
+
+/// One Ollama server
+#[derive(Debug)]
+struct Host {
+    /// Base URL without a trailing slash
+    url: String,
+    /// Requests currently holding a `Lease` on this host
+    in_flight: AtomicUsize,
+    /// Set after a failure; the host is skipped until then
+    unhealthy_until: Mutex<Option<Instant>>,
+}
+
+impl Host {
+    fn is_healthy(&self) -> bool {
+        self.unhealthy_until.lock().unwrap().is_none_or(|until| until <= Instant::now())
+    }
+}
+
+/// The Ollama servers chat requests are spread over (`--ollama-url a,b,...`).
+/// A host that can't be reached sits out `--upstream-cooldown-secs` before it's tried again.
+#[derive(Debug)]
+pub struct Upstreams {
+    hosts: Vec<Arc<Host>>,
+    strategy: BalanceStrategy,
+    /// Round-robin position, also used to break least-connections ties
+    next: AtomicUsize,
+    cooldown: Duration,
+}
+
+impl Upstreams {
+    pub fn new(config: &Config) -> Self {
+        let hosts = config
+            .ollama_urls
+            .iter()
+            .map(|url| {
+                Arc::new(Host { url: url.clone(), in_flight: AtomicUsize::new(0), unhealthy_until: Mutex::new(None) })
+            })
+            .collect();
+        Self { hosts, strategy: config.balance_strategy, next: AtomicUsize::new(0), cooldown: config.upstream_cooldown }
+    }
+
+    /// The host for the next request. When every host is cooling down, the one that failed
+    /// longest ago is tried anyway rather than failing without asking.
+    pub fn pick(&self) -> Lease {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let ordered = (0..self.hosts.len()).map(|offset| &self.hosts[(start + offset) % self.hosts.len()]);
+        let healthy: Vec<_> = ordered.filter(|host| host.is_healthy()).collect();
+        let host = match self.strategy {
+            BalanceStrategy::RoundRobin => healthy.first().copied(),
+            // `min_by_key` keeps the first of equals, so ties still rotate
+            BalanceStrategy::LeastConnections => {
+                healthy.iter().copied().min_by_key(|host| host.in_flight.load(Ordering::SeqCst))
+            }
+        };
+        let host = host.cloned().unwrap_or_else(|| {
+            self.hosts.iter().min_by_key(|host| *host.unhealthy_until.lock().unwrap()).cloned().unwrap()
+        });
+        host.in_flight.fetch_add(1, Ordering::SeqCst);
+        Lease { host, cooldown: self.cooldown }
+    }
+
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        self.hosts.iter().map(|host| host.url.as_str())
+    }
+
+    pub fn strategy(&self) -> BalanceStrategy {
+        self.strategy
+    }
+}
+
+/// A request's claim on one host, counted as in flight until dropped
+#[derive(Debug)]
+pub struct Lease {
+    host: Arc<Host>,
+    cooldown: Duration,
+}
+
+impl Lease {
+    pub fn url(&self) -> &str {
+        &self.host.url
+    }
+
+    /// Join a path such as `/api/chat` onto the host's base URL
+    pub fn endpoint(&self, path: &str) -> String {
+        format!("{}/{}", self.host.url, path.trim_start_matches('/'))
+    }
+
+    /// Route around this host for the cooldown
+    pub fn mark_unhealthy(&self) {
+        *self.host.unhealthy_until.lock().unwrap() = Some(Instant::now() + self.cooldown);
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.host.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
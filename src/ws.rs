@@ -0,0 +1,170 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
+
+use axum::{
+    extract::{
+        ws::{rejection::WebSocketUpgradeRejection, Message as WsMessage, WebSocket, WebSocketUpgrade},
+        ConnectInfo, State,
+    },
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use serde_json::Value;
+use tracing::debug;
+
+use crate::{
+    apply_system_prompt, chat_stream, query_request,
+    rate_limit::{check_limits, client_ip, ClientKey},
+    AppError, AppState, EventStream, StreamEvent,
+};
+
+/// `GET /ws`: bidirectional chat. The client sends `{"prompt": ...}` (plus any `/chat` query
+/// parameter as a JSON field) and gets tokens back as text frames, then `{"done": true, "done_reason": ...}`.
+/// Sending `{"action": "stop"}` interrupts the current generation, answered by `{"done": true, "interrupted": true}`.
+///
+/// Every prompt frame counts against the rate limits like a `/chat` request would, on top of the
+/// upgrade itself, and is answered with the same error when one is exceeded.
+pub async fn ws_handler(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Response {
+    let client = SocketClient {
+        ip: client_ip(&headers, peer, state.config.trust_forwarded_for),
+        api_key: ClientKey::api_key(&headers, &state.config.api_keys),
+    };
+    match ws {
+        Ok(ws) => ws.on_upgrade(move |socket| handle_socket(socket, state, client)),
+        // A plain HTTP request: say so in the same JSON as every other error
+        Err(rejection) => AppError::rejected(rejection).into_response(),
+    }
+}
+
+/// Who a socket's prompts count against: its IP and API key are settled at the upgrade, while each
+/// frame names its own session
+struct SocketClient {
+    ip: IpAddr,
+    api_key: Option<ClientKey>,
+}
+
+async fn handle_socket(mut socket: WebSocket, state: AppState, client: SocketClient) {
+    // At most one generation per socket; dropping it aborts the upstream request
+    let mut generation: Option<EventStream> = None;
+
+    loop {
+        tokio::select! {
+            incoming = socket.recv() => {
+                let text = match incoming {
+                    Some(Ok(WsMessage::Text(text))) => text,
+                    Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                let reply = match handle_client_message(&state, &client, &text, &mut generation).await {
+                    Ok(Some(reply)) => reply,
+                    Ok(None) => continue,
+                    Err(error) => error.to_json(),
+                };
+                if socket.send(WsMessage::Text(reply)).await.is_err() {
+                    break;
+                }
+            }
+            event = next_event(&mut generation) => {
+                let frame = match event {
+                    Some(StreamEvent::Token(token)) => token,
+                    Some(StreamEvent::ToolCalls(calls)) => serde_json::json!({ "tool_calls": calls }).to_string(),
+                    Some(StreamEvent::Error(error)) => {
+                        generation = None;
+                        error.to_json()
+                    }
+                    Some(StreamEvent::Done(usage)) => {
+                        generation = None;
+                        serde_json::json!({ "done": true, "done_reason": usage.done_reason }).to_string()
+                    }
+                    // The relay ended without saying so
+                    None => {
+                        generation = None;
+                        serde_json::json!({ "done": true }).to_string()
+                    }
+                };
+                if socket.send(WsMessage::Text(frame)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    debug!(generating = generation.is_some(), "WebSocket closed");
+}
+
+/// Apply one client frame: start a generation, or stop the current one. Returns a frame to send back, if any.
+async fn handle_client_message(
+    state: &AppState,
+    client: &SocketClient,
+    text: &str,
+    generation: &mut Option<EventStream>,
+) -> Result<Option<String>, AppError> {
+    let frame: serde_json::Map<String, Value> = serde_json::from_str(text)
+        .map_err(|err| AppError::bad_request(format!("expected a JSON object: {}", err)))?;
+
+    if frame.get("action").and_then(Value::as_str) == Some("stop") {
+        // Dropping the stream closes its channel, so the relay drops the upstream response right away
+        // and Ollama stops generating, rather than finishing an answer nobody reads
+        return Ok(generation.take().map(|_| serde_json::json!({ "done": true, "interrupted": true }).to_string()));
+    }
+    if generation.is_some() {
+        return Err(AppError::bad_request("a generation is already running; send {\"action\":\"stop\"} first"));
+    }
+
+    let params = frame_params(frame);
+    let session = params.get("session_id").filter(|id| !id.is_empty()).map(|id| ClientKey::Session(id.clone()));
+    check_limits(state, client.ip, client.api_key.iter().cloned().chain(session).collect())?;
+
+    let (mut request, session) = query_request(state, &params).await?;
+    let permit = state.generation_permit().await?;
+    apply_system_prompt(state, &mut request);
+    *generation = Some(chat_stream(state, request, session, permit).await);
+    Ok(None)
+}
+
+/// Flatten a JSON frame into the same string parameters `GET /chat` takes
+fn frame_params(frame: serde_json::Map<String, Value>) -> HashMap<String, String> {
+    frame
+        .into_iter()
+        .map(|(key, value)| match value {
+            Value::String(s) => (key, s),
+            other => (key, other.to_string()),
+        })
+        .collect()
+}
+
+/// The next event of the running generation, or pending forever when idle
+async fn next_event(generation: &mut Option<EventStream>) -> Option<StreamEvent> {
+    match generation {
+        Some(stream) => stream.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[tokio::test]
+    async fn every_prompt_frame_counts_against_the_rate_limit() {
+        let args = ["chatbot_api", "--mock-backend", "--rate-limit", "1"];
+        let state = AppState::new(Config::parse_from(args)).await;
+        let client = SocketClient { ip: [127, 0, 0, 1].into(), api_key: None };
+        let prompt = r#"{"prompt": "hi"}"#;
+
+        let mut generation = None;
+        assert!(handle_client_message(&state, &client, prompt, &mut generation).await.unwrap().is_none());
+        generation = None;
+        let err = handle_client_message(&state, &client, prompt, &mut generation).await.unwrap_err();
+        assert!(matches!(err, AppError::RateLimited { scope: "IP address", .. }));
+    }
+}